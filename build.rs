@@ -0,0 +1,14 @@
+use std::env::var_os;
+
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(atomic_free)");
+
+    // Blocks may be freed concurrently by threads not owning the heap,
+    // so the pages need the atomic `thread_free` list.
+    if ["THREAD_LOCAL", "STD_MUTEX", "SPIN_MUTEX", "LOCK_API"]
+        .iter()
+        .any(|feature| var_os(format!("CARGO_FEATURE_{feature}")).is_some())
+    {
+        println!("cargo::rustc-cfg=atomic_free");
+    }
+}
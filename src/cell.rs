@@ -0,0 +1,156 @@
+#[cfg(debug_assertions)]
+use crate::error::{report, Error};
+use crate::heap::Heap;
+use crate::Mimalloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Wrap [`Mimalloc`] inside an [`UnsafeCell`] without any lock and implement [`GlobalAlloc`].
+///
+/// It is intended for single-threaded programs, e.g. WASI or embedded binaries without
+/// interrupts that allocate. See [`MimallocCell::with_os_allocator`] for the requirements.
+///
+/// With debug assertions, using it while it is already in use is reported as
+/// [`Error::Reentrant`](crate::error::Error): allocation returns null and deallocation leaks
+/// the block.
+pub struct MimallocCell<A: GlobalAlloc> {
+    allocator: UnsafeCell<Mimalloc<A>>,
+    /// Whether the allocator is in use, to catch misuse in debug builds.
+    #[cfg(debug_assertions)]
+    in_use: AtomicBool,
+}
+
+// it can only be created by `with_os_allocator`, whose caller guarantees no concurrent access
+unsafe impl<A: GlobalAlloc> Sync for MimallocCell<A> {}
+
+impl<A: GlobalAlloc> MimallocCell<A> {
+    /// Create a new [`MimallocCell`] instance with an OS allocator.
+    ///
+    /// # Safety
+    ///
+    /// The instance must not be used by multiple threads concurrently, and must not be used
+    /// reentrantly, e.g. from a deferred free hook, from the OS allocator, or from a signal or
+    /// interrupt handler.
+    pub const unsafe fn with_os_allocator(os_alloc: A) -> Self {
+        Self::with_allocator_instance(Mimalloc::with_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_zeroed_os_allocator`].
+    ///
+    /// # Safety
+    ///
+    /// See [`with_os_allocator`](Self::with_os_allocator).
+    pub const unsafe fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self::with_allocator_instance(Mimalloc::with_zeroed_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_guard_pages`].
+    ///
+    /// # Safety
+    ///
+    /// See [`with_os_allocator`](Self::with_os_allocator).
+    #[cfg(feature = "guard_pages")]
+    pub const unsafe fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self::with_allocator_instance(Mimalloc::with_guard_pages(os_alloc))
+    }
+
+    const fn with_allocator_instance(allocator: Mimalloc<A>) -> Self {
+        Self {
+            allocator: UnsafeCell::new(allocator),
+            #[cfg(debug_assertions)]
+            in_use: AtomicBool::new(false),
+        }
+    }
+
+    #[cfg(feature = "deferred_free")]
+    /// See [`Mimalloc::register_deferred_free`].
+    pub fn register_deferred_free(&self, hook: crate::DeferredFreeHook<A>) {
+        self.with_allocator(|allocator| allocator.register_deferred_free(hook));
+    }
+
+    #[cfg(feature = "segment_map")]
+    /// See [`Mimalloc::register_fallback`].
+    pub fn register_fallback(&self, fallback: &'static (dyn GlobalAlloc + Sync)) {
+        self.with_allocator(|allocator| allocator.register_fallback(fallback));
+    }
+
+    #[cfg(feature = "guarded")]
+    /// See [`Mimalloc::set_guarded_sample_rate`].
+    pub fn set_guarded_sample_rate(&self, rate: usize) {
+        self.with_allocator(|allocator| allocator.set_guarded_sample_rate(rate));
+    }
+
+    /// See [`Mimalloc::collect`].
+    pub fn collect(&self) {
+        self.with_allocator(Mimalloc::collect);
+    }
+
+    /// See [`Mimalloc::usable_size`]. It does not access the allocator, so it can be used
+    /// while the allocator is in use.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mimalloc::usable_size`].
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        Heap::usable_size(ptr)
+    }
+
+    /// See [`Mimalloc::realloc_zeroed`].
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.realloc_zeroed(ptr, layout, new_size))
+            .unwrap_or(null_mut())
+    }
+
+    /// Run `f` with the allocator, or report [`Error::Reentrant`] and return [`None`]
+    /// if the allocator is already in use (only checked with debug assertions).
+    fn with_allocator<T>(&self, f: impl FnOnce(&mut Mimalloc<A>) -> T) -> Option<T> {
+        #[cfg(debug_assertions)]
+        {
+            // only load and store, which are available on targets without atomic swap
+            if self.in_use.load(Ordering::Relaxed) {
+                report(Error::Reentrant);
+                return None;
+            }
+            self.in_use.store(true, Ordering::Relaxed);
+        }
+        // the caller of `with_os_allocator` guarantees exclusive access
+        let result = f(unsafe { &mut *self.allocator.get() });
+        #[cfg(debug_assertions)]
+        self.in_use.store(false, Ordering::Relaxed);
+        Some(result)
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for MimallocCell<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.alloc(layout))
+            .unwrap_or(null_mut())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.alloc_zeroed(layout))
+            .unwrap_or(null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_allocator(|allocator| allocator.dealloc(ptr, layout));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.realloc(ptr, layout, new_size))
+            .unwrap_or(null_mut())
+    }
+}
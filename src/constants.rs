@@ -29,7 +29,7 @@ pub const MI_BIN_HUGE: usize = 64;
 pub const MI_MAX_ALIGN_SIZE: usize = 16;
 
 pub const MI_ALIGN_W: usize = {
-    assert!(MI_MAX_ALIGN_SIZE % MI_INTPTR_SIZE == 0);
+    assert!(MI_MAX_ALIGN_SIZE.is_multiple_of(MI_INTPTR_SIZE));
     let result = MI_MAX_ALIGN_SIZE / MI_INTPTR_SIZE;
     match result {
         1 | 2 | 4 => result,
@@ -39,5 +39,28 @@ pub const MI_ALIGN_W: usize = {
 
 pub const MI_PAGE_HUGE_ALIGN: usize = 256 * 1024;
 
+/// Maximum number of live and quarantined guarded allocations.
+#[cfg(feature = "guarded")]
+pub const MI_GUARDED_MAX: usize = 256;
+/// Number of freed guarded allocations kept inaccessible by each heap.
+#[cfg(feature = "guarded")]
+pub const MI_GUARDED_QUARANTINE: usize = 16;
+
+/// Number of entries of each free list scanned by debug builds for blocks that do not look free.
+#[cfg(feature = "double_free")]
+pub const MI_DEBUG_DOUBLE_FREE_SCAN: usize = 16;
+
+/// Size of the canary and the delta after each block.
+#[cfg(feature = "padding")]
+pub const MI_PADDING_SIZE: usize = 2 * MI_INTPTR_SIZE;
+/// The byte to fill the bytes between the requested size and the padding with.
+#[cfg(feature = "padding")]
+pub const MI_DEBUG_PADDING: u8 = 0xDE;
+
 pub const MI_MAX_EXTEND_SIZE: usize = 4096;
 pub const MI_MIN_EXTEND: usize = 1;
+
+#[cfg(feature = "secure")]
+pub const MI_MAX_SLICE_SHIFT: usize = 6;
+#[cfg(feature = "secure")]
+pub const MI_MIN_SLICES: usize = 2;
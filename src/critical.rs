@@ -0,0 +1,127 @@
+use crate::heap::Heap;
+use crate::Mimalloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ptr::null_mut;
+use critical_section::Mutex;
+
+/// Wrap [`Mimalloc`] inside a [`critical_section::Mutex`] and implement [`GlobalAlloc`].
+///
+/// Both allocation and deallocation run inside a critical section, so it can be used in
+/// interrupt handlers on bare-metal targets without threads, where `spin::Mutex` could
+/// deadlock.
+///
+/// Reentrant calls from inside the critical section (e.g. from a deferred free hook or from the
+/// OS allocator) cannot use the allocator: allocation returns null and deallocation leaks the
+/// block.
+pub struct MimallocCriticalSection<A: GlobalAlloc> {
+    allocator: Mutex<RefCell<Mimalloc<A>>>,
+}
+
+impl<A: GlobalAlloc> MimallocCriticalSection<A> {
+    /// See [`Mimalloc::with_os_allocator`].
+    pub const fn with_os_allocator(os_alloc: A) -> Self {
+        Self::with_allocator_instance(Mimalloc::with_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_zeroed_os_allocator`].
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self::with_allocator_instance(Mimalloc::with_zeroed_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_guard_pages`].
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self::with_allocator_instance(Mimalloc::with_guard_pages(os_alloc))
+    }
+
+    const fn with_allocator_instance(allocator: Mimalloc<A>) -> Self {
+        Self {
+            allocator: Mutex::new(RefCell::new(allocator)),
+        }
+    }
+
+    #[cfg(feature = "deferred_free")]
+    /// See [`Mimalloc::register_deferred_free`].
+    pub fn register_deferred_free(&self, hook: crate::DeferredFreeHook<A>) {
+        self.with_allocator(|allocator| allocator.register_deferred_free(hook));
+    }
+
+    #[cfg(feature = "segment_map")]
+    /// See [`Mimalloc::register_fallback`].
+    pub fn register_fallback(&self, fallback: &'static (dyn GlobalAlloc + Sync)) {
+        self.with_allocator(|allocator| allocator.register_fallback(fallback));
+    }
+
+    #[cfg(feature = "guarded")]
+    /// See [`Mimalloc::set_guarded_sample_rate`].
+    pub fn set_guarded_sample_rate(&self, rate: usize) {
+        self.with_allocator(|allocator| allocator.set_guarded_sample_rate(rate));
+    }
+
+    /// See [`Mimalloc::collect`].
+    pub fn collect(&self) {
+        self.with_allocator(Mimalloc::collect);
+    }
+
+    /// See [`Mimalloc::usable_size`]. It does not enter a critical section.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mimalloc::usable_size`].
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        Heap::usable_size(ptr)
+    }
+
+    /// See [`Mimalloc::realloc_zeroed`].
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.realloc_zeroed(ptr, layout, new_size))
+            .unwrap_or(null_mut())
+    }
+
+    /// Run `f` with the allocator inside a critical section,
+    /// or return [`None`] if the allocator is already in use.
+    fn with_allocator<T>(&self, f: impl FnOnce(&mut Mimalloc<A>) -> T) -> Option<T> {
+        critical_section::with(|cs| {
+            let mut allocator = self.allocator.borrow(cs).try_borrow_mut().ok()?;
+            Some(f(&mut allocator))
+        })
+    }
+}
+
+impl<A: GlobalAlloc + Default> Default for MimallocCriticalSection<A> {
+    fn default() -> Self {
+        Self::with_os_allocator(A::default())
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for MimallocCriticalSection<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.alloc(layout))
+            .unwrap_or(null_mut())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.alloc_zeroed(layout))
+            .unwrap_or(null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_allocator(|allocator| allocator.dealloc(ptr, layout));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with_allocator(|allocator| allocator.realloc(ptr, layout, new_size))
+            .unwrap_or(null_mut())
+    }
+}
@@ -0,0 +1,48 @@
+//! A small bump region for allocations that cannot be served by the heap.
+
+use crate::constants::*;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const EMERGENCY_SIZE: usize = 16 * 1024;
+
+#[repr(align(16))]
+struct Region(UnsafeCell<[u8; EMERGENCY_SIZE]>);
+
+// the memory is only accessed through disjoint allocations
+unsafe impl Sync for Region {}
+
+const _: () = assert!(align_of::<Region>() == MI_MAX_ALIGN_SIZE);
+
+static REGION: Region = Region(UnsafeCell::new([0; EMERGENCY_SIZE]));
+/// Number of bytes allocated from the start of [`REGION`].
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate from the emergency region. Returns null if it is exhausted.
+///
+/// The memory is never reused, so it should only be used in rare cases.
+pub fn alloc(layout: Layout) -> *mut u8 {
+    let start = REGION.0.get() as usize;
+    let mut used = USED.load(Ordering::Relaxed);
+    loop {
+        let end = (start + used)
+            .checked_next_multiple_of(layout.align())
+            .and_then(|aligned| (aligned - start).checked_add(layout.size()))
+            .filter(|&end| end <= EMERGENCY_SIZE);
+        let Some(end) = end else {
+            return null_mut();
+        };
+        match USED.compare_exchange_weak(used, end, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return unsafe { REGION.0.get().cast::<u8>().add(end - layout.size()) },
+            Err(new_used) => used = new_used,
+        }
+    }
+}
+
+/// Whether `p` is allocated from the emergency region.
+pub fn contains(p: *const u8) -> bool {
+    let start = REGION.0.get() as usize;
+    (start..start + EMERGENCY_SIZE).contains(&(p as usize))
+}
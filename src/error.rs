@@ -0,0 +1,97 @@
+//! Reporting errors detected by the allocator.
+
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// An error detected by the allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The allocator is called by a thread that is already holding its lock,
+    /// e.g. from a deferred free hook or from the OS allocator.
+    ///
+    /// Nested allocations are served from a small emergency region that is never reused,
+    /// and other nested operations are skipped.
+    ///
+    /// It is also reported when a [`MimallocCell`](crate::MimallocCell) is used while it is
+    /// already in use, with debug assertions. The nested allocation returns null, and the nested
+    /// deallocation leaks the block.
+    Reentrant,
+    /// A free list pointer does not point to a block in the same page, which is usually caused by
+    /// a use-after-free write or a buffer overflow. The list is truncated after `block`,
+    /// leaking the rest of the list.
+    ///
+    /// It is only detected with the `secure` feature.
+    CorruptedFreeList {
+        /// Address of the block that contains the corrupted pointer.
+        block: usize,
+    },
+    /// A block is freed while it is already free. The second free is ignored.
+    ///
+    /// It is only detected with the `double_free` feature. Blocks freed twice without the owning
+    /// heap (by another thread, or through a lock wrapper) are detected when the owner collects
+    /// them, and the blocks freed by other threads between the two frees may be leaked.
+    /// With the `guarded` feature, it is also detected for quarantined guarded allocations.
+    DoubleFree {
+        /// Address of the block.
+        block: usize,
+    },
+    /// The canary after a block is overwritten, which is usually caused by a buffer overflow.
+    /// The block is still freed.
+    ///
+    /// It is only detected with the `padding` feature.
+    BufferOverflow {
+        /// The freed pointer.
+        ptr: usize,
+        /// The requested size of the allocation, or the usable size if it is unknown.
+        size: usize,
+    },
+    /// A guard page or a freed slot of a guarded allocation is accessed.
+    ///
+    /// It is only detected with the `guarded` feature, and reported by the handler installed by
+    /// `install_guarded_fault_handler`.
+    GuardedFault {
+        /// The faulting address.
+        addr: usize,
+        /// The pointer of the guarded allocation.
+        ptr: usize,
+        /// The requested size of the guarded allocation.
+        size: usize,
+        /// Whether the allocation is already freed, i.e. a use-after-free.
+        freed: bool,
+    },
+    /// A pointer that is not allocated by this allocator is freed. The free is ignored.
+    ///
+    /// It is only detected with the `segment_map` feature.
+    InvalidFree {
+        /// The freed pointer.
+        ptr: usize,
+    },
+}
+
+/// Handler of the errors detected by the allocator. See [`register_error`].
+///
+/// It is called by the thread that detects the error, possibly while holding the allocator lock,
+/// so it should not allocate.
+pub type ErrorHandler = fn(error: Error);
+
+static ERROR_HANDLER: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+/// Register a handler that is called when the allocator detects an error.
+/// A new handler replaces the old one. Errors are ignored if there is no handler.
+///
+/// Similar to `mi_register_error` (the extra `arg` is not supported).
+pub fn register_error(handler: ErrorHandler) {
+    ERROR_HANDLER.store(handler as *mut (), Ordering::Release);
+}
+
+// only used by `MimallocCell` without the features that detect errors
+#[cfg_attr(not(debug_assertions), allow(dead_code))]
+pub(crate) fn report(error: Error) {
+    let handler = ERROR_HANDLER.load(Ordering::Acquire);
+    if !handler.is_null() {
+        // only `ErrorHandler`s are stored in `ERROR_HANDLER`
+        let handler = unsafe { core::mem::transmute::<*mut (), ErrorHandler>(handler) };
+        handler(error);
+    }
+}
@@ -0,0 +1,157 @@
+//! Locking allocators around `fork` with `pthread_atfork`.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use libc::pthread_atfork;
+
+/// Maximum number of locks that can be registered.
+pub const MAX_FORK_LOCKS: usize = 64;
+
+/// Storage of a type-erased [`ForkLock::Guard`].
+type GuardStorage = MaybeUninit<[usize; 2]>;
+
+/// A lock that is held during `fork`.
+pub trait ForkLock: Sync + 'static {
+    /// Guard that releases the lock when dropped. It must fit in [`GuardStorage`].
+    type Guard;
+
+    /// Acquire the lock.
+    fn lock(&'static self) -> Self::Guard;
+
+    /// Repair the protected state in the child before the lock is released. Other threads do
+    /// not exist in the child, so they may have left their work unfinished.
+    fn child(_guard: &mut Self::Guard) {}
+}
+
+struct VTable {
+    lock: unsafe fn(lock: *const (), guard: *mut GuardStorage),
+    child: unsafe fn(guard: *mut GuardStorage),
+    unlock: unsafe fn(guard: *mut GuardStorage),
+}
+
+trait HasVTable {
+    const VTABLE: VTable;
+}
+
+impl<T: ForkLock> HasVTable for T {
+    const VTABLE: VTable = VTable {
+        lock: lock_erased::<T>,
+        child: child_erased::<T>,
+        unlock: unlock_erased::<T>,
+    };
+}
+
+unsafe fn lock_erased<T: ForkLock>(lock: *const (), guard: *mut GuardStorage) {
+    guard.cast::<T::Guard>().write((*lock.cast::<T>()).lock());
+}
+
+unsafe fn child_erased<T: ForkLock>(guard: *mut GuardStorage) {
+    T::child(&mut *guard.cast::<T::Guard>());
+}
+
+unsafe fn unlock_erased<T: ForkLock>(guard: *mut GuardStorage) {
+    drop(guard.cast::<T::Guard>().read());
+}
+
+struct Slot {
+    /// The registered lock, or null if the slot is free.
+    lock: AtomicPtr<()>,
+    /// Set after the registration completes.
+    vtable: AtomicPtr<VTable>,
+    /// Whether the lock is held by the fork handlers.
+    locked: AtomicBool,
+    guard: UnsafeCell<GuardStorage>,
+}
+
+// `guard` is only accessed by the fork handlers while holding the lock
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            lock: AtomicPtr::new(null_mut()),
+            vtable: AtomicPtr::new(null_mut()),
+            locked: AtomicBool::new(false),
+            guard: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+static SLOTS: [Slot; MAX_FORK_LOCKS] = [const { Slot::new() }; MAX_FORK_LOCKS];
+static HANDLERS_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Hold `lock` during `fork`, and release it in both the parent and the child.
+///
+/// Registering the same lock again has no effect. Returns `false` if there are already
+/// [`MAX_FORK_LOCKS`] locks or the handlers cannot be installed.
+pub fn register<T: ForkLock>(lock: &'static T) -> bool {
+    const {
+        assert!(size_of::<T::Guard>() <= size_of::<GuardStorage>());
+        assert!(align_of::<T::Guard>() <= align_of::<GuardStorage>());
+    }
+
+    if HANDLERS_INSTALLED
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+        && unsafe { pthread_atfork(Some(prepare), Some(parent), Some(child)) } != 0
+    {
+        HANDLERS_INSTALLED.store(false, Ordering::Release);
+        return false;
+    }
+
+    let lock = lock as *const T as *mut ();
+    for slot in &SLOTS {
+        match slot
+            .lock
+            .compare_exchange(null_mut(), lock, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                let vtable = &T::VTABLE as *const VTable;
+                slot.vtable.store(vtable.cast_mut(), Ordering::Release);
+                return true;
+            }
+            Err(registered) if registered == lock => return true,
+            Err(_) => {}
+        }
+    }
+    false
+}
+
+/// Acquire the registered locks in the reverse order of registration.
+unsafe extern "C" fn prepare() {
+    for slot in SLOTS.iter().rev() {
+        let vtable = slot.vtable.load(Ordering::Acquire);
+        if !vtable.is_null() {
+            ((*vtable).lock)(slot.lock.load(Ordering::Relaxed), slot.guard.get());
+            slot.locked.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe extern "C" fn parent() {
+    release();
+}
+
+unsafe extern "C" fn child() {
+    // only the forking thread exists in the child, and it is the holder of all locks
+    for slot in &SLOTS {
+        if slot.locked.load(Ordering::Relaxed) {
+            let vtable = slot.vtable.load(Ordering::Acquire);
+            ((*vtable).child)(slot.guard.get());
+        }
+    }
+    release();
+}
+
+/// Release the locks acquired in [`prepare`].
+unsafe fn release() {
+    for slot in &SLOTS {
+        if slot.locked.load(Ordering::Relaxed) {
+            let vtable = slot.vtable.load(Ordering::Acquire);
+            slot.locked.store(false, Ordering::Relaxed);
+            ((*vtable).unlock)(slot.guard.get());
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use core::alloc::GlobalAlloc;
+
+/// An OS allocator that can change the protection of the memory it allocates.
+///
+/// It is used to place guard pages in segments, see [`Mimalloc::with_guard_pages`](crate::Mimalloc::with_guard_pages).
+///
+/// # Safety
+///
+/// [`page_size`](ProtectAlloc::page_size) must return a power of two, and
+/// [`dealloc`](GlobalAlloc::dealloc) must accept memory containing protected pages.
+pub unsafe trait ProtectAlloc: GlobalAlloc {
+    /// The granularity of protection, i.e. the OS page size.
+    fn page_size(&self) -> usize;
+
+    /// Make `size` bytes at `ptr` inaccessible. A failure only loses the guard.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `size` are multiples of [`page_size`](ProtectAlloc::page_size), and the memory
+    /// is allocated by this allocator.
+    unsafe fn protect(&self, ptr: *mut u8, size: usize);
+}
+
+/// Type-erased [`ProtectAlloc`] of the OS allocator, stored in the heap.
+#[derive(Clone, Copy)]
+pub struct GuardPages {
+    page_size: unsafe fn(os_alloc: *const ()) -> usize,
+    protect: unsafe fn(os_alloc: *const (), ptr: *mut u8, size: usize),
+}
+
+impl GuardPages {
+    pub const fn new<A: ProtectAlloc>() -> Self {
+        Self {
+            page_size: page_size_erased::<A>,
+            protect: protect_erased::<A>,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `os_alloc` is of the type that this is created with.
+    pub unsafe fn page_size<A: GlobalAlloc>(&self, os_alloc: &A) -> usize {
+        (self.page_size)(os_alloc as *const A as *const ())
+    }
+
+    /// # Safety
+    ///
+    /// `os_alloc` is of the type that this is created with. See also [`ProtectAlloc::protect`].
+    pub unsafe fn protect<A: GlobalAlloc>(&self, os_alloc: &A, ptr: *mut u8, size: usize) {
+        (self.protect)(os_alloc as *const A as *const (), ptr, size)
+    }
+}
+
+unsafe fn page_size_erased<A: ProtectAlloc>(os_alloc: *const ()) -> usize {
+    (*os_alloc.cast::<A>()).page_size()
+}
+
+unsafe fn protect_erased<A: ProtectAlloc>(os_alloc: *const (), ptr: *mut u8, size: usize) {
+    (*os_alloc.cast::<A>()).protect(ptr, size)
+}
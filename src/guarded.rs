@@ -0,0 +1,144 @@
+//! Sampled allocations in dedicated segments flanked by guard pages, similar to `MI_GUARDED`.
+//!
+//! The live guarded segments are recorded in a global table, so that a fault can be attributed
+//! to the guarded allocation it hits.
+
+use crate::constants::*;
+use crate::segment::Segment;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// The allocation in a guarded segment.
+#[derive(Clone, Copy)]
+pub struct GuardedBlock {
+    /// The pointer returned to the user, placed right before the trailing guard page.
+    pub ptr: usize,
+    /// The requested size, only used to report faults.
+    #[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+    pub size: usize,
+}
+
+static GUARDED_SEGMENTS: [AtomicPtr<Segment>; MI_GUARDED_MAX] =
+    [const { AtomicPtr::new(null_mut()) }; MI_GUARDED_MAX];
+
+/// Record a guarded segment. Returns `false` if there are already [`MI_GUARDED_MAX`] of them.
+pub fn insert(segment: *mut Segment) -> bool {
+    GUARDED_SEGMENTS.iter().any(|slot| {
+        slot.compare_exchange(null_mut(), segment, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+pub fn remove(segment: *mut Segment) {
+    if let Some(slot) = GUARDED_SEGMENTS
+        .iter()
+        .find(|slot| slot.load(Ordering::Relaxed) == segment)
+    {
+        slot.store(null_mut(), Ordering::Release);
+    }
+}
+
+/// Find the guarded allocation whose segment contains `addr`, and whether it is freed.
+#[cfg(feature = "mmap")]
+fn find(addr: usize) -> Option<(GuardedBlock, bool)> {
+    GUARDED_SEGMENTS.iter().find_map(|slot| {
+        // the info of a recorded segment is accessible unless it is being freed concurrently
+        let segment = unsafe { slot.load(Ordering::Acquire).as_ref() }?;
+        let block = segment.guarded().filter(|_| segment.contains(addr))?;
+        let page = segment.page_of_ptr(block.ptr as *const u8);
+        Some((block, unsafe { page.as_ref() }.all_free()))
+    })
+}
+
+#[cfg(feature = "mmap")]
+pub use fault::install_guarded_fault_handler;
+
+#[cfg(feature = "mmap")]
+mod fault {
+    use super::find;
+    use crate::error::{report, Error};
+    use core::cell::UnsafeCell;
+    use core::ffi::{c_int, c_void};
+    use core::mem::MaybeUninit;
+    use core::ptr::null_mut;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use libc::{
+        sigaction, sigemptyset, siginfo_t, SA_ONSTACK, SA_SIGINFO, SIGBUS, SIGSEGV, SIG_DFL,
+        SIG_IGN,
+    };
+
+    const SIGNALS: [c_int; 2] = [SIGSEGV, SIGBUS];
+
+    type Handler = extern "C" fn(c_int, *mut siginfo_t, *mut c_void);
+
+    /// The handlers replaced by [`install_guarded_fault_handler`]. Faults outside guarded
+    /// allocations are forwarded to them.
+    struct PreviousHandlers([UnsafeCell<MaybeUninit<sigaction>>; 2]);
+
+    // only written before the handlers are installed
+    unsafe impl Sync for PreviousHandlers {}
+
+    static PREVIOUS: PreviousHandlers =
+        PreviousHandlers([const { UnsafeCell::new(MaybeUninit::uninit()) }; 2]);
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+    /// Install `SIGSEGV` and `SIGBUS` handlers that report faults in guarded allocations as
+    /// [`Error::GuardedFault`] via [`register_error`](crate::error::register_error).
+    ///
+    /// The error handler is called inside the signal handler, so it should be async-signal-safe.
+    /// Afterwards the previous handlers are restored and the fault is raised again, which usually
+    /// terminates the process.
+    ///
+    /// Other faults are forwarded to the previous handlers, e.g. the stack overflow handler of
+    /// `std`. The handlers run on the alternate signal stack if the thread has one.
+    ///
+    /// Installing again has no effect. Returns `false` if the handlers cannot be installed.
+    pub fn install_guarded_fault_handler() -> bool {
+        if INSTALLED.swap(true, Ordering::AcqRel) {
+            return true;
+        }
+        for (signal, previous) in SIGNALS.into_iter().zip(&PREVIOUS.0) {
+            let mut action = unsafe { MaybeUninit::<sigaction>::zeroed().assume_init() };
+            action.sa_sigaction = handle_fault as Handler as usize;
+            action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+            unsafe { sigemptyset(&mut action.sa_mask) };
+            if unsafe { sigaction(signal, &action, (*previous.get()).as_mut_ptr()) } != 0 {
+                INSTALLED.store(false, Ordering::Release);
+                return false;
+            }
+        }
+        true
+    }
+
+    extern "C" fn handle_fault(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+        let Some(previous) = SIGNALS.iter().position(|&s| s == signal) else {
+            return;
+        };
+        // written by `sigaction` before this handler is installed
+        let previous = unsafe { (*PREVIOUS.0[previous].get()).assume_init_ref() };
+        let addr = unsafe { (*info).si_addr() } as usize;
+        if let Some((block, freed)) = find(addr) {
+            report(Error::GuardedFault {
+                addr,
+                ptr: block.ptr,
+                size: block.size,
+                freed,
+            });
+        } else if previous.sa_sigaction != SIG_DFL && previous.sa_sigaction != SIG_IGN {
+            // not ours, e.g. a stack overflow handled by `std`
+            if previous.sa_flags & SA_SIGINFO != 0 {
+                let handler =
+                    unsafe { core::mem::transmute::<usize, Handler>(previous.sa_sigaction) };
+                handler(signal, info, context);
+            } else {
+                let handler = unsafe {
+                    core::mem::transmute::<usize, extern "C" fn(c_int)>(previous.sa_sigaction)
+                };
+                handler(signal);
+            }
+            return;
+        }
+        // returning retries the faulting access, which is then handled by the previous handler
+        unsafe { sigaction(signal, previous, null_mut()) };
+    }
+}
@@ -1,6 +1,12 @@
 use crate::constants::*;
+#[cfg(feature = "guard_pages")]
+use crate::guard::GuardPages;
 use crate::list::{LinkedList, LinkedListItem};
 use crate::page::{empty_page, Page};
+#[cfg(atomic_free)]
+use crate::page::{take_atomic, Block};
+#[cfg(feature = "secure")]
+use crate::random::Random;
 use crate::segment::{PageKind, Segment};
 use crate::utils::{
     bin_for_size, wsize_from_size, BLOCK_SIZE_FOR_BIN, WSIZE_RANGE_IN_SAME_SMALL_BIN,
@@ -9,15 +15,83 @@ use crate::utils::{
 use crate::{DeferredFreeHandle, DeferredFreeHook};
 use core::alloc::GlobalAlloc;
 use core::ptr::{null_mut, NonNull};
+#[cfg(atomic_free)]
+use core::sync::atomic::AtomicPtr;
+
+/// Settings of the heaps created by a wrapper, chosen by its constructor.
+#[cfg(any(
+    feature = "std_mutex",
+    feature = "spin_mutex",
+    feature = "thread_local"
+))]
+#[derive(Clone, Copy, Default)]
+pub struct HeapOptions {
+    /// The OS allocator returns zeroed memory.
+    pub os_zero: bool,
+    /// Place guard pages at the end of each page.
+    #[cfg(feature = "guard_pages")]
+    pub guard_pages: Option<GuardPages>,
+}
+
+#[cfg(any(
+    feature = "std_mutex",
+    feature = "spin_mutex",
+    feature = "thread_local"
+))]
+impl HeapOptions {
+    pub const fn new() -> Self {
+        Self {
+            os_zero: false,
+            #[cfg(feature = "guard_pages")]
+            guard_pages: None,
+        }
+    }
+
+    pub const fn with_os_zero(mut self) -> Self {
+        self.os_zero = true;
+        self
+    }
+
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages<A: crate::ProtectAlloc>(mut self) -> Self {
+        self.guard_pages = Some(GuardPages::new::<A>());
+        self
+    }
+}
 
 pub struct Heap {
     pages_free_direct: [NonNull<Page>; MI_SMALL_WSIZE_MAX + 1],
     pages: [LinkedList<Page>; MI_BIN_HUGE + 1],
+    /// Pages without free blocks. They are not searched when allocating.
+    pages_full: LinkedList<Page>,
     small_free_segments: LinkedList<Segment>,
+    /// The OS allocator returns zeroed memory.
+    os_zero: bool,
+    /// Index of the shard in [`MimallocShardedWrapper`](crate::MimallocShardedWrapper).
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    shard: usize,
+    /// Blocks freed by other threads in full pages of this heap.
+    #[cfg(feature = "thread_local")]
+    thread_delayed_free: AtomicPtr<Block>,
     #[cfg(feature = "deferred_free")]
     heartbeat: u64,
     #[cfg(feature = "deferred_free")]
     calling_deferred_free: bool,
+    #[cfg(feature = "secure")]
+    random: Random,
+    #[cfg(feature = "guard_pages")]
+    guard_pages: Option<GuardPages>,
+    /// Serve one in this many allocations from a guarded segment, or none if it is 0.
+    #[cfg(feature = "guarded")]
+    guarded_sample_rate: usize,
+    /// Number of allocations until the next guarded one.
+    #[cfg(feature = "guarded")]
+    guarded_countdown: usize,
+    /// Freed guarded segments, kept inaccessible until the quarantine is full.
+    #[cfg(feature = "guarded")]
+    guarded_quarantine: LinkedList<Segment>,
+    #[cfg(feature = "guarded")]
+    guarded_quarantine_len: usize,
 }
 
 impl Default for Heap {
@@ -31,21 +105,40 @@ impl Heap {
         Self {
             pages_free_direct: [empty_page(); MI_SMALL_WSIZE_MAX + 1],
             pages: [const { LinkedList::new() }; MI_BIN_HUGE + 1],
+            pages_full: LinkedList::new(),
             small_free_segments: LinkedList::new(),
+            os_zero: false,
+            #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+            shard: 0,
+            #[cfg(feature = "thread_local")]
+            thread_delayed_free: AtomicPtr::new(null_mut()),
             #[cfg(feature = "deferred_free")]
             heartbeat: 0,
             #[cfg(feature = "deferred_free")]
             calling_deferred_free: false,
+            #[cfg(feature = "secure")]
+            random: Random::new(),
+            #[cfg(feature = "guard_pages")]
+            guard_pages: None,
+            #[cfg(feature = "guarded")]
+            guarded_sample_rate: 0,
+            #[cfg(feature = "guarded")]
+            guarded_countdown: 0,
+            #[cfg(feature = "guarded")]
+            guarded_quarantine: LinkedList::new(),
+            #[cfg(feature = "guarded")]
+            guarded_quarantine_len: 0,
         }
     }
 
+    /// Allocate a block of at least `size` bytes, and return it with its page.
     #[inline(never)]
     pub fn malloc<A: GlobalAlloc>(
         &mut self,
         size: usize,
         os_alloc: &A,
         #[cfg(feature = "deferred_free")] deferred_free_hook: Option<DeferredFreeHook<A>>,
-    ) -> *mut u8 {
+    ) -> Option<(NonNull<u8>, &mut Page)> {
         let result = if size <= MI_SMALL_SIZE_MAX {
             let page = self.get_small_free_page(size);
             Page::malloc_fast(
@@ -78,25 +171,69 @@ impl Heap {
             },
             "allocated from a block smaller than requested"
         );
-        result.map_or(null_mut(), |(ptr, _)| ptr.as_ptr())
+        result
     }
 
+    /// Allocate `size` bytes aligned to `align`, which are zeroed if `zero`. With the `padding`
+    /// feature, a canary is written after the requested size, which is checked when the block
+    /// is freed.
     pub fn malloc_aligned<A: GlobalAlloc>(
         &mut self,
         size: usize,
         align: usize,
+        zero: bool,
+        os_alloc: &A,
+        #[cfg(feature = "deferred_free")] deferred_free_hook: Option<DeferredFreeHook<A>>,
+    ) -> *mut u8 {
+        #[cfg(feature = "guarded")]
+        if self.should_sample_guarded(size) {
+            let p = self.malloc_guarded(size, align, os_alloc);
+            if !p.is_null() {
+                if zero {
+                    unsafe { p.write_bytes(0, size) };
+                }
+                return p;
+            }
+        }
+        #[cfg(feature = "padding")]
+        let Some(requested_size) = size.checked_add(MI_PADDING_SIZE) else {
+            return null_mut();
+        };
+        #[cfg(not(feature = "padding"))]
+        let requested_size = size;
+        let p = self.malloc_aligned_unpadded(
+            requested_size,
+            align,
+            zero,
+            os_alloc,
+            #[cfg(feature = "deferred_free")]
+            deferred_free_hook,
+        );
+        #[cfg(feature = "padding")]
+        if !p.is_null() {
+            Page::init_padding(p, size);
+        }
+        p
+    }
+
+    fn malloc_aligned_unpadded<A: GlobalAlloc>(
+        &mut self,
+        size: usize,
+        align: usize,
+        zero: bool,
         os_alloc: &A,
         #[cfg(feature = "deferred_free")] deferred_free_hook: Option<DeferredFreeHook<A>>,
     ) -> *mut u8 {
         debug_assert!(align.is_power_of_two());
 
         if align <= MI_INTPTR_SIZE {
-            return self.malloc(
+            let result = self.malloc(
                 size,
                 os_alloc,
                 #[cfg(feature = "deferred_free")]
                 deferred_free_hook,
             );
+            return Self::zero_block(result, size, zero);
         }
         if size >= usize::MAX - align {
             return null_mut();
@@ -106,15 +243,15 @@ impl Heap {
             let page = self.get_small_free_page(size);
             let free = unsafe { page.as_ref() }.free();
             if !free.is_null() && (free as usize & (align - 1) == 0) {
-                return Page::malloc_fast(
+                let result = Page::malloc_fast(
                     page,
                     self,
                     size,
                     os_alloc,
                     #[cfg(feature = "deferred_free")]
                     deferred_free_hook,
-                )
-                .map_or(null_mut(), |(ptr, _)| ptr.as_ptr());
+                );
+                return Self::zero_block(result, size, zero);
             }
         }
 
@@ -126,15 +263,411 @@ impl Heap {
         )
         .map_or(null_mut(), |(ptr, page)| {
             page.set_aligned(true);
+            if zero {
+                page.zero_block(ptr, size + align - 1);
+            }
             let aligned_addr = (ptr.as_ptr() as usize + align - 1) & !(align - 1);
             aligned_addr as *mut u8
         })
     }
 
+    /// Zero the first `size` bytes of the allocated block if `zero`, and return it.
+    fn zero_block(result: Option<(NonNull<u8>, &mut Page)>, size: usize, zero: bool) -> *mut u8 {
+        result.map_or(null_mut(), |(ptr, page)| {
+            if zero {
+                page.zero_block(ptr, size);
+            }
+            ptr.as_ptr()
+        })
+    }
+
+    // mi_heap_malloc_use_guarded
+    #[cfg(feature = "guarded")]
+    fn should_sample_guarded(&mut self, size: usize) -> bool {
+        if self.guarded_sample_rate == 0 || size == 0 || self.guard_pages.is_none() {
+            return false;
+        }
+        self.guarded_countdown -= 1;
+        if self.guarded_countdown == 0 {
+            self.guarded_countdown = self.guarded_sample_rate;
+            true
+        } else {
+            false
+        }
+    }
+
+    // mi_heap_malloc_guarded_aligned
+    /// Allocate from a new guarded segment, with `p + size` at the start of the trailing guard
+    /// page (rounded down to `align`).
+    #[cfg(feature = "guarded")]
+    fn malloc_guarded<A: GlobalAlloc>(
+        &mut self,
+        size: usize,
+        align: usize,
+        os_alloc: &A,
+    ) -> *mut u8 {
+        let Some((segment, page)) = self.segment_alloc(PageKind::Guarded { size, align }, os_alloc)
+        else {
+            return null_mut();
+        };
+        let segment = unsafe { segment.as_ref() };
+        let page_size = segment.page_size(page.as_ptr());
+        unsafe { &mut *page.as_ptr() }.init(
+            page_size,
+            page_size,
+            #[cfg(feature = "secure")]
+            &mut self.random,
+        );
+        // take the only block
+        if let Some((_, page)) = Page::malloc_fast(
+            page,
+            self,
+            page_size,
+            os_alloc,
+            #[cfg(feature = "deferred_free")]
+            None,
+        ) {
+            // the page is never searched for free blocks
+            page.set_aligned(true);
+            page.set_full(true);
+        }
+        unsafe { self.pages_full.push_back(page) };
+        segment.guarded().map_or(null_mut(), |block| block.ptr as _)
+    }
+
+    /// Make a freed guarded page inaccessible, and release the oldest one in the quarantine.
+    #[cfg(feature = "guarded")]
+    pub fn quarantine_guarded<A: GlobalAlloc>(
+        &mut self,
+        page: NonNull<Page>,
+        full: bool,
+        os_alloc: &A,
+    ) {
+        if full {
+            unsafe { self.pages_full.remove(page) };
+        } else {
+            self.page_queue_remove(unsafe { &mut *page.as_ptr() });
+        }
+        let segment = unsafe { NonNull::new_unchecked(Segment::of_ptr(page.as_ptr())) };
+        let seg = unsafe { segment.as_ref() };
+        if let Some(guard_pages) = self.guard_pages {
+            let slot = seg.page_payload_addr(page.as_ptr()) as *mut u8;
+            unsafe { guard_pages.protect(os_alloc, slot, seg.page_size(page.as_ptr())) };
+        }
+        unsafe { self.guarded_quarantine.push_back(segment) };
+        self.guarded_quarantine_len += 1;
+        if self.guarded_quarantine_len > MI_GUARDED_QUARANTINE {
+            self.release_guarded(os_alloc);
+        }
+    }
+
+    /// Release the oldest segment in the quarantine.
+    #[cfg(feature = "guarded")]
+    fn release_guarded<A: GlobalAlloc>(&mut self, os_alloc: &A) {
+        if let Some(segment) = NonNull::new(self.guarded_quarantine.first()) {
+            unsafe { self.guarded_quarantine.remove(segment) };
+            self.guarded_quarantine_len -= 1;
+            let page = unsafe { segment.as_ref() }.page_of_ptr(segment.as_ptr().cast());
+            unsafe { page.write_bytes(0, 1) };
+            Segment::remove_a_page(segment, self, os_alloc);
+        }
+    }
+
+    /// Release all segments in the quarantine.
+    #[cfg(feature = "guarded")]
+    pub fn release_guarded_quarantine<A: GlobalAlloc>(&mut self, os_alloc: &A) {
+        while self.guarded_quarantine_len > 0 {
+            self.release_guarded(os_alloc);
+        }
+    }
+
+    // mi_expand, and the in-place case of _mi_heap_realloc_zero
+    /// Resize the allocation of `size` bytes at `p` to `new_size` bytes without moving it.
+    /// It succeeds if `new_size` fits in the block and at least half of the block is still used.
+    /// With `zero`, the bytes after `size` are zeroed.
+    ///
+    /// Only the page of `p` is read, so it does not need the owning heap.
+    /// Guarded allocations are never resized in place, to keep them next to the guard page.
+    pub fn expand(p: *mut u8, size: usize, new_size: usize, zero: bool) -> bool {
+        let Some(segment) = Segment::of_allocated_ptr(p) else {
+            return false;
+        };
+        #[cfg(feature = "guarded")]
+        if segment.guarded().is_some() {
+            return false;
+        }
+        let usable_size = Page::usable_size(segment, p);
+        if new_size > usable_size || new_size < usable_size / 2 {
+            return false;
+        }
+        if zero && new_size > size {
+            unsafe { ((p as usize + size) as *mut u8).write_bytes(0, new_size - size) };
+        }
+        #[cfg(feature = "padding")]
+        Page::init_padding(p, new_size);
+        true
+    }
+
+    /// Whether `p` is in a huge page. Huge pages are only collected when allocating another
+    /// huge block, so a huge block freed by another thread stays in memory until then.
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api"))]
+    pub fn is_huge(p: *const u8) -> bool {
+        Segment::of_allocated_ptr(p)
+            .is_some_and(|segment| unsafe { segment.page_of_ptr(p).as_ref() }.bin() == MI_BIN_HUGE)
+    }
+
+    // mi_usable_size
+    /// The number of bytes that can be used in the allocation at `p`, or 0 if it is null or,
+    /// with the `segment_map` feature, not allocated by this crate.
+    ///
+    /// With the `padding` feature, it is the requested size, since the rest of the block is
+    /// checked for overflows. Like [`expand`](Self::expand), it only reads the page of `p`.
+    pub fn usable_size(p: *const u8) -> usize {
+        let Some(segment) = Segment::of_allocated_ptr(p) else {
+            return 0;
+        };
+        #[cfg(feature = "padding")]
+        return Page::requested_size(segment, p);
+        #[cfg(not(feature = "padding"))]
+        Page::usable_size(segment, p)
+    }
+
     pub fn free<A: GlobalAlloc>(&mut self, p: *mut u8, os_alloc: &A) {
-        if let Some(segment) = unsafe { Segment::of_ptr(p).as_ref() } {
-            let page = segment.page_of_ptr(p);
-            Page::free_block(self, page, segment.into(), p, os_alloc);
+        if let Some(segment) = Segment::of_freed_ptr(p) {
+            #[cfg(feature = "padding")]
+            Page::check_padding(segment, p);
+            self.free_in_segment(segment, p, os_alloc);
+        }
+    }
+
+    fn free_in_segment<A: GlobalAlloc>(&mut self, segment: &Segment, p: *mut u8, os_alloc: &A) {
+        let page = segment.page_of_ptr(p);
+        Page::free_block(self, page, segment.into(), p, os_alloc);
+    }
+
+    /// Free a block taken from a delayed free list.
+    /// It has been checked when it is pushed to the list.
+    #[cfg(atomic_free)]
+    fn free_delayed<A: GlobalAlloc>(&mut self, block: *mut Block, os_alloc: &A) {
+        let segment = unsafe { &*Segment::of_ptr(block) };
+        self.free_in_segment(segment, block.cast(), os_alloc);
+    }
+
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    pub const fn set_shard(&mut self, shard: usize) {
+        self.shard = shard;
+    }
+
+    pub const fn set_os_zero(&mut self) {
+        self.os_zero = true;
+    }
+
+    #[cfg(any(
+        feature = "std_mutex",
+        feature = "spin_mutex",
+        feature = "thread_local"
+    ))]
+    pub const fn set_options(&mut self, options: HeapOptions) {
+        self.os_zero = options.os_zero;
+        #[cfg(feature = "guard_pages")]
+        {
+            self.guard_pages = options.guard_pages;
+        }
+    }
+
+    #[cfg(feature = "secure")]
+    pub const fn set_random_seed(&mut self, seed: u64) {
+        self.random = Random::with_seed(seed);
+    }
+
+    #[cfg(feature = "guard_pages")]
+    pub const fn set_guard_pages(&mut self, guard_pages: GuardPages) {
+        self.guard_pages = Some(guard_pages);
+    }
+
+    #[cfg(all(feature = "guarded", feature = "thread_local"))]
+    pub const fn guarded_sample_rate(&self) -> usize {
+        self.guarded_sample_rate
+    }
+
+    #[cfg(feature = "guarded")]
+    pub const fn set_guarded_sample_rate(&mut self, rate: usize) {
+        self.guarded_sample_rate = rate;
+        self.guarded_countdown = rate;
+    }
+
+    /// Free a block without accessing the owning heap.
+    /// The block is collected by the owner when it looks for free blocks.
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api"))]
+    pub fn free_remote<'a>(
+        p: *mut u8,
+        delayed_free: impl FnOnce(&'a Segment) -> &'a AtomicPtr<Block>,
+    ) {
+        if let Some(segment) = Segment::of_freed_ptr(p) {
+            #[cfg(feature = "padding")]
+            Page::check_padding(segment, p);
+            Page::free_block_mt(segment, p, delayed_free);
+        }
+    }
+
+    /// Free a block that may be owned by another heap.
+    #[cfg(feature = "thread_local")]
+    pub fn free_mt<A: GlobalAlloc>(&mut self, p: *mut u8, os_alloc: &A) {
+        if let Some(segment) = Segment::of_freed_ptr(p) {
+            #[cfg(feature = "padding")]
+            Page::check_padding(segment, p);
+            if core::ptr::eq(segment.heap(), self) {
+                self.free_in_segment(segment, p, os_alloc);
+            } else {
+                Page::free_block_mt(segment, p, |segment| unsafe {
+                    Heap::thread_delayed_free(segment.heap())
+                });
+            }
+        }
+    }
+
+    /// Get the list of blocks freed by other threads in full pages of `heap`.
+    ///
+    /// # Safety
+    ///
+    /// `heap` must be valid during `'a`.
+    #[cfg(feature = "thread_local")]
+    pub unsafe fn thread_delayed_free<'a>(heap: *mut Self) -> &'a AtomicPtr<Block> {
+        &(*heap).thread_delayed_free
+    }
+
+    // _mi_heap_delayed_free
+    /// Free the blocks pushed to a delayed free list by [`Page::free_block_mt`].
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api"))]
+    pub fn delayed_free_collect<A: GlobalAlloc>(
+        &mut self,
+        delayed_free: &AtomicPtr<Block>,
+        os_alloc: &A,
+    ) {
+        for block in take_atomic(delayed_free) {
+            self.free_delayed(block, os_alloc);
+        }
+    }
+
+    #[cfg(feature = "thread_local")]
+    fn thread_delayed_free_collect<A: GlobalAlloc>(&mut self, os_alloc: &A) {
+        for block in take_atomic(&self.thread_delayed_free) {
+            self.free_delayed(block, os_alloc);
+        }
+    }
+
+    // mi_heap_collect_abandon
+    /// Abandon all segments so that they can be reclaimed by other heaps, and reset the heap.
+    /// Blocks in the abandoned segments are still valid and can be freed by any thread.
+    #[cfg(feature = "thread_local")]
+    pub fn abandon(&mut self) {
+        // stop other threads from pushing to `thread_delayed_free`
+        self.for_each_page(|mut page| unsafe { page.as_mut() }.abandon());
+
+        // give the delayed blocks back to their pages, to be collected by the reclaiming heap
+        for block in take_atomic(&self.thread_delayed_free) {
+            let segment = unsafe { &*Segment::of_ptr(block) };
+            // the page never uses delayed free now
+            Page::free_block_mt(segment, block.cast(), |_| unreachable!());
+        }
+
+        // Collect the segments before publishing any of them. A published segment can be
+        // reclaimed by another thread, which relinks or frees its pages while this heap still
+        // walks them. The links of `small_free_segments` are reused, as it is reset below.
+        let mut segments = LinkedList::new();
+
+        // quarantined pages have no blocks in use, so they are freed when reclaimed
+        #[cfg(feature = "guarded")]
+        while let Some(segment) = NonNull::new(self.guarded_quarantine.first()) {
+            unsafe { self.guarded_quarantine.remove(segment) };
+            unsafe { segment.as_ref() }.set_heap(null_mut());
+            unsafe { segments.push_back(segment) };
+        }
+
+        let this = self as *mut Self;
+        self.for_each_page(|page| {
+            let segment = Segment::of_ptr(page.as_ptr());
+            // a segment can contain multiple pages
+            if unsafe { (*segment).heap() } == this {
+                unsafe { (*segment).set_heap(null_mut()) };
+                unsafe { segments.push_back(NonNull::new_unchecked(segment)) };
+            }
+        });
+
+        while let Some(segment) = NonNull::new(segments.first()) {
+            unsafe { segments.remove(segment) };
+            Segment::abandon(segment);
+        }
+
+        *self = Self {
+            os_zero: self.os_zero,
+            #[cfg(feature = "secure")]
+            random: self.random,
+            #[cfg(feature = "guard_pages")]
+            guard_pages: self.guard_pages,
+            #[cfg(feature = "guarded")]
+            guarded_sample_rate: self.guarded_sample_rate,
+            #[cfg(feature = "guarded")]
+            guarded_countdown: self.guarded_countdown,
+            ..Self::new()
+        };
+    }
+
+    // _mi_segment_try_reclaim_abandoned
+    /// Take over all abandoned segments.
+    #[cfg(feature = "thread_local")]
+    fn reclaim_abandoned<A: GlobalAlloc>(&mut self, os_alloc: &A) {
+        for mut segment in Segment::take_abandoned() {
+            let segment = unsafe { segment.as_mut() };
+            segment.set_heap(self);
+            if !segment.is_full() {
+                self.push_small_free_segment(segment);
+            }
+            let mut used = segment.used();
+            for mut page in segment.pages() {
+                let page_mut = unsafe { page.as_mut() };
+                // guarded pages are never searched for free blocks
+                #[cfg(feature = "guarded")]
+                if segment.guarded().is_some() {
+                    page_mut.free_collect();
+                    page_mut.set_full(true);
+                    unsafe { self.pages_full.push_back(page) };
+                    if page_mut.all_free() {
+                        // the block was freed while the segment was abandoned
+                        self.quarantine_guarded(page, true, os_alloc);
+                    }
+                    continue;
+                }
+                page_mut.set_full(false);
+                page_mut.free_collect();
+                self.page_queue_push_back(page);
+                // retire free pages immediately, otherwise they are hoarded by this heap
+                if page_mut.all_free() {
+                    self.retire_page(page, false, os_alloc);
+                    used -= 1;
+                    if used == 0 {
+                        // the segment has been freed
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// See [`Page::reset_delayed_freeing`].
+    #[cfg(all(atomic_free, feature = "mmap"))]
+    pub fn reset_delayed_freeing(&self) {
+        self.for_each_page(|page| unsafe { page.as_ref() }.reset_delayed_freeing());
+    }
+
+    #[cfg(any(feature = "thread_local", all(atomic_free, feature = "mmap")))]
+    fn for_each_page(&self, mut f: impl FnMut(NonNull<Page>)) {
+        for pq in self.pages.iter().chain([&self.pages_full]) {
+            let mut p = pq.first();
+            while let Some(page) = NonNull::new(p) {
+                p = unsafe { page.as_ref() }.next();
+                f(page);
+            }
         }
     }
 
@@ -153,9 +686,16 @@ impl Heap {
         #[cfg(feature = "deferred_free")]
         self.deferred_free(false, os_alloc, deferred_free_hook);
 
+        #[cfg(feature = "thread_local")]
+        self.thread_delayed_free_collect(os_alloc);
+
         let page = if size <= MI_LARGE_SIZE_MAX {
             self.find_free_page(size, os_alloc)
         } else {
+            // huge pages are never searched for free blocks, so they need to be
+            // collected here to reclaim the ones freed by other threads
+            #[cfg(atomic_free)]
+            self.collect_queue(MI_BIN_HUGE, os_alloc);
             self.alloc_huge_page(size, os_alloc)
         };
 
@@ -228,13 +768,28 @@ impl Heap {
                 break;
             }
 
-            page.extend();
+            page.extend(
+                #[cfg(feature = "secure")]
+                &mut self.random,
+            );
             if page.immediate_available() {
                 break;
             }
 
             page.set_full(true);
+
+            // collect again in case another thread freed a block before `set_full`
+            #[cfg(atomic_free)]
+            {
+                page.free_collect();
+                if page.immediate_available() {
+                    page.set_full(false);
+                    break;
+                }
+            }
+
             self.page_queue_remove(page);
+            unsafe { self.pages_full.push_back(page.into()) };
 
             p = next;
         }
@@ -262,7 +817,12 @@ impl Heap {
             .map_or(null_mut(), |(segment, mut p)| {
                 let page_size = unsafe { segment.as_ref() }.page_size(p.as_ptr());
                 let page = unsafe { p.as_mut() };
-                page.init(page_size, block_size);
+                page.init(
+                    page_size,
+                    block_size,
+                    #[cfg(feature = "secure")]
+                    &mut self.random,
+                );
                 self.page_queue_push_front(page);
                 p.as_ptr()
             })
@@ -279,7 +839,14 @@ impl Heap {
         self.page_queue_first_update(page.block_size(), page);
     }
 
-    pub fn page_queue_push_back(&mut self, page: NonNull<Page>) {
+    /// Move a page from the full page queue back to its page queue.
+    pub fn page_unfull(&mut self, mut page: NonNull<Page>) {
+        unsafe { page.as_mut() }.set_full(false);
+        unsafe { self.pages_full.remove(page) };
+        self.page_queue_push_back(page);
+    }
+
+    fn page_queue_push_back(&mut self, page: NonNull<Page>) {
         let pq = &mut self.pages[unsafe { page.as_ref() }.bin()];
         if unsafe { pq.push_back(page) } {
             self.page_queue_first_update(unsafe { page.as_ref() }.block_size(), page.as_ptr());
@@ -311,10 +878,13 @@ impl Heap {
         block_size: usize,
         os_alloc: &A,
     ) -> Option<(NonNull<Segment>, NonNull<Page>)> {
+        #[cfg(feature = "thread_local")]
+        self.reclaim_abandoned(os_alloc);
+
         if block_size < MI_SMALL_PAGE_SIZE / 8 {
             match unsafe { self.small_free_segments.first().as_mut() } {
                 None => {
-                    let (segment, page) = Segment::alloc(PageKind::Small, os_alloc)?;
+                    let (segment, page) = self.segment_alloc(PageKind::Small, os_alloc)?;
                     unsafe { self.small_free_segments.push_back(segment) };
                     Some((segment, page))
                 }
@@ -333,10 +903,31 @@ impl Heap {
             } else {
                 PageKind::Huge(block_size)
             };
-            Segment::alloc(page_kind, os_alloc)
+            self.segment_alloc(page_kind, os_alloc)
         }
     }
 
+    fn segment_alloc<A: GlobalAlloc>(
+        &mut self,
+        page_kind: PageKind,
+        os_alloc: &A,
+    ) -> Option<(NonNull<Segment>, NonNull<Page>)> {
+        let (segment, page) = Segment::alloc(
+            page_kind,
+            os_alloc,
+            self.os_zero,
+            #[cfg(feature = "guard_pages")]
+            self.guard_pages,
+        )?;
+        #[cfg(feature = "thread_local")]
+        unsafe { segment.as_ref() }.set_heap(self);
+        #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+        unsafe {
+            (*segment.as_ptr()).set_shard(self.shard)
+        };
+        Some((segment, page))
+    }
+
     pub fn push_small_free_segment(&mut self, segment: &mut Segment) {
         unsafe { self.small_free_segments.push_back(segment.into()) };
     }
@@ -354,7 +945,9 @@ impl Heap {
         full: bool,
         os_alloc: &A,
     ) {
-        if !full {
+        if full {
+            unsafe { self.pages_full.remove(page) };
+        } else {
             self.page_queue_remove(unsafe { page.as_mut() });
         }
         unsafe { page.write_bytes(0, 1) };
@@ -363,15 +956,41 @@ impl Heap {
     }
 
     pub fn collect<A: GlobalAlloc>(&mut self, os_alloc: &A) {
-        for i in 0..self.pages.len() {
-            let mut p = self.pages[i].first();
-            while let Some(mut page) = NonNull::new(p) {
-                let page_mut = unsafe { page.as_mut() };
-                page_mut.free_collect();
-                p = page_mut.next();
-                if page_mut.all_free() {
-                    self.retire_page(page, false, os_alloc);
-                }
+        #[cfg(feature = "thread_local")]
+        {
+            self.reclaim_abandoned(os_alloc);
+            self.thread_delayed_free_collect(os_alloc);
+        }
+
+        for bin in 0..self.pages.len() {
+            self.collect_queue(bin, os_alloc);
+        }
+
+        let mut p = self.pages_full.first();
+        while let Some(mut page) = NonNull::new(p) {
+            let page_mut = unsafe { page.as_mut() };
+            page_mut.free_collect();
+            p = page_mut.next();
+            if page_mut.all_free() {
+                self.retire_page(page, true, os_alloc);
+            }
+        }
+    }
+
+    /// Free the huge pages whose blocks have been freed by other threads.
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api"))]
+    pub fn collect_huge<A: GlobalAlloc>(&mut self, os_alloc: &A) {
+        self.collect_queue(MI_BIN_HUGE, os_alloc);
+    }
+
+    fn collect_queue<A: GlobalAlloc>(&mut self, bin: usize, os_alloc: &A) {
+        let mut p = self.pages[bin].first();
+        while let Some(mut page) = NonNull::new(p) {
+            let page_mut = unsafe { page.as_mut() };
+            page_mut.free_collect();
+            p = page_mut.next();
+            if page_mut.all_free() {
+                self.retire_page(page, false, os_alloc);
             }
         }
     }
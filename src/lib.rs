@@ -17,18 +17,63 @@
 //! [Mimalloc](https://github.com/microsoft/mimalloc) implemented in Rust
 //! (not a binding to the C library) with only basic features.
 //!
-//! Lock-free multi-threading, security features, and some performance enhancements are not
-//! implemented.
+//! Most security features and some performance enhancements are not implemented.
 //!
 //! It can be used in `no_std` environments.
 //!
 //! # Crate Features
 //!
 //! - **mmap** - Provide [`MimallocMmap`] that uses `mmap` as OS allocator for segments.
+//!   With a mutex feature, also provide
+//!   [`register_fork_handlers`](MimallocMutexWrapper::register_fork_handlers) that holds the
+//!   allocator lock during `fork` via `pthread_atfork`.
+//! - **random_mmap** - Make [`MmapAlloc`] map segments at random addresses, aligned to the
+//!   segment size, instead of the ones picked by the kernel. If a random address is taken, it falls
+//!   back to the latter. Implies `mmap`, and only takes effect on 64-bit platforms.
 //! - **std_mutex** - Provide [`MimallocMutexWrapper`] that wraps [`Mimalloc`] inside
 //!   [`std::sync::Mutex`] and implements [`GlobalAlloc`].
 //! - **spin_mutex** - Provide [`MimallocMutexWrapper`] that wraps [`Mimalloc`] inside
 //!   [`spin::Mutex`] that can be used in `no_std` environments.
+//!
+//!   Both also provide [`MimallocShardedWrapper`] that spreads threads across multiple
+//!   [`Mimalloc`] instances. With `std` (implied by `std_mutex`), reentrant calls from the
+//!   thread holding the lock are detected and reported via `error::register_error`
+//!   instead of deadlocking. Without `std`, reentrant allocations deadlock,
+//!   see [`MimallocLockWrapper`].
+//! - **lock_api** - Provide [`MimallocRawMutexWrapper`] that wraps [`Mimalloc`] inside
+//!   a [`lock_api::Mutex`] of any [`RawMutex`](lock_api::RawMutex), such as `parking_lot`
+//!   or a custom lock. It can be used together with `std_mutex` or `spin_mutex`.
+//! - **critical_section** - Provide [`MimallocCriticalSection`] that wraps [`Mimalloc`] inside
+//!   a [`critical_section::Mutex`] and implements [`GlobalAlloc`], for bare-metal targets where
+//!   interrupt handlers may allocate.
+//! - **thread_local** - Provide [`MimallocThreadLocal`] that gives each thread its own heap,
+//!   frees blocks of other threads without locking, and implements [`GlobalAlloc`].
+//! - **thread_cache** - Provide [`MimallocCacheWrapper`] that puts a per-thread cache of small
+//!   free blocks in front of [`MimallocMutexWrapper`]. Requires `std_mutex` or `spin_mutex`.
+//! - **secure** - Encode the free list pointers with random per-page keys, and report corrupted
+//!   free lists via [`error::register_error`] instead of returning wild pointers. New blocks are
+//!   added to the free lists in a random order. See [`Mimalloc::set_random_seed`]. Disables the
+//!   thread cache of `thread_cache`, whose blocks would bypass these checks.
+//! - **double_free** - Detect freeing a block that is already free, report it via
+//!   [`error::register_error`] and ignore the second free. Implies `secure`.
+//! - **segment_map** - Record the live segments in a bitmap over the address space, so that
+//!   freeing a pointer not allocated by this crate is reported via [`error::register_error`]
+//!   and ignored, instead of corrupting memory. Such pointers can also be forwarded to a
+//!   fallback allocator, see [`Mimalloc::register_fallback`]. It takes 8 MiB of address space in `.bss`
+//!   on 64-bit targets, which is only committed when used.
+//! - **padding** - Write a keyed canary after each allocation, and report overwriting it via
+//!   [`error::register_error`] when the block is freed. Implies `secure`.
+//! - **zero_on_free** - Overwrite freed blocks with zeros, except the first word that holds the
+//!   free list pointer.
+//! - **fill_on_free** - Overwrite freed blocks with `0xDF` instead. In debug builds, the fill is
+//!   checked when the block is allocated again, to catch writes after free.
+//! - **guard_pages** - Provide [`Mimalloc::with_guard_pages`] (and the same constructor of each
+//!   wrapper) that protects the last OS page of each page, with an OS allocator implementing
+//!   [`ProtectAlloc`]. With `mmap`, [`MmapAlloc`] implements it with `mprotect`.
+//! - **guarded** - Provide [`Mimalloc::set_guarded_sample_rate`] that serves one in N allocations
+//!   from a dedicated slot flanked by guard pages, and keeps freed slots inaccessible for a while.
+//!   Implies `guard_pages`. With `mmap`, also provide [`install_guarded_fault_handler`] that
+//!   reports faults in the slots via [`error::register_error`].
 //! - **deferred_free** - Enable registering a hook to complete deferred free events.
 //!   See the documentation of [`mi_register_deferred_free`](https://microsoft.github.io/mimalloc/group__extended.html#ga3460a6ca91af97be4058f523d3cb8ece).
 
@@ -39,11 +84,17 @@ mod constants;
 mod heap;
 mod list;
 mod page;
+#[cfg(any(feature = "secure", feature = "random_mmap"))]
+mod random;
+mod realloc;
 mod segment;
+#[cfg(feature = "segment_map")]
+mod segment_map;
 mod utils;
 
 use core::alloc::{GlobalAlloc, Layout};
 use heap::Heap;
+pub use utils::{good_size, size_classes};
 
 /* wrapper around `heap::Heap` to defined the public API. */
 
@@ -52,20 +103,45 @@ use heap::Heap;
 /// `A` is the type of the OS allocator for segments.
 ///
 /// To use it as the [`global_allocator`], wrap it inside a lock and implement [`GlobalAlloc`].
-/// See [`MimallocMutexWrapper`].
+/// See [`MimallocMutexWrapper`], or [`MimallocCell`] for single-threaded programs.
 #[derive(Default)]
 pub struct Mimalloc<A: GlobalAlloc> {
     heap: Heap,
     os_alloc: A,
     #[cfg(feature = "deferred_free")]
     deferred_free_hook: Option<DeferredFreeHook<A>>,
+    #[cfg(feature = "segment_map")]
+    fallback: Option<&'static (dyn GlobalAlloc + Sync)>,
 }
 
+/// An OS allocator whose [`alloc`](GlobalAlloc::alloc) returns zeroed memory,
+/// e.g. fresh pages from `mmap`.
+///
+/// It lets [`Mimalloc::alloc_zeroed`] skip clearing blocks that have never been used.
+/// See [`Mimalloc::with_zeroed_os_allocator`].
+///
+/// # Safety
+///
+/// All memory returned by [`alloc`](GlobalAlloc::alloc) must be zero-initialized.
+pub unsafe trait ZeroedAlloc: GlobalAlloc {}
+
 #[cfg(feature = "deferred_free")]
 pub mod deferred_free;
 #[cfg(feature = "deferred_free")]
 use deferred_free::*;
 
+#[cfg(feature = "guard_pages")]
+mod guard;
+#[cfg(feature = "guard_pages")]
+pub use guard::ProtectAlloc;
+
+#[cfg(feature = "guarded")]
+mod guarded;
+#[cfg(all(feature = "guarded", feature = "mmap"))]
+pub use guarded::install_guarded_fault_handler;
+
+pub mod error;
+
 unsafe impl<A: GlobalAlloc> Send for Mimalloc<A> {}
 
 impl<A: GlobalAlloc> Mimalloc<A> {
@@ -76,9 +152,60 @@ impl<A: GlobalAlloc> Mimalloc<A> {
             os_alloc,
             #[cfg(feature = "deferred_free")]
             deferred_free_hook: None,
+            #[cfg(feature = "segment_map")]
+            fallback: None,
         }
     }
 
+    /// Create a new [`Mimalloc`] instance with an OS allocator that returns zeroed memory,
+    /// so that [`alloc_zeroed`](Self::alloc_zeroed) only clears blocks that have been used before.
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: ZeroedAlloc,
+    {
+        let mut allocator = Self::with_os_allocator(os_alloc);
+        allocator.heap.set_os_zero();
+        allocator
+    }
+
+    /// Create a new [`Mimalloc`] instance with an OS allocator that places guard pages
+    /// at the end of each page, so that linear overflows fault immediately.
+    ///
+    /// Each page loses one OS page of capacity. Guard pages are not used if the OS page size
+    /// is too large for small pages.
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: ProtectAlloc,
+    {
+        let mut allocator = Self::with_os_allocator(os_alloc);
+        allocator
+            .heap
+            .set_guard_pages(guard::GuardPages::new::<A>());
+        allocator
+    }
+
+    /// Seed the random number generator used for the free list keys and the free list order.
+    ///
+    /// By default, it is seeded from ASLR-randomized addresses, and from the OS random source
+    /// with the `std` feature. Without `std`, a seed from a hardware random source is stronger.
+    #[cfg(feature = "secure")]
+    pub const fn set_random_seed(&mut self, seed: u64) {
+        self.heap.set_random_seed(seed);
+    }
+
+    /// Serve one in `rate` allocations from a dedicated slot flanked by guard pages, so that
+    /// overflows, underflows and use-after-free accesses fault immediately. Freed slots stay
+    /// inaccessible until a few more guarded allocations of this allocator are freed.
+    ///
+    /// A rate of 0 disables sampling, which is the default. It only takes effect with guard pages,
+    /// see [`with_guard_pages`](Self::with_guard_pages). Faults can be reported with
+    /// `install_guarded_fault_handler`.
+    #[cfg(feature = "guarded")]
+    pub const fn set_guarded_sample_rate(&mut self, rate: usize) {
+        self.heap.set_guarded_sample_rate(rate);
+    }
+
     #[cfg(feature = "deferred_free")]
     /// Register a hook to complete deferred free when the allocator needs more memory.
     /// A new hook replaces the old one.
@@ -90,6 +217,17 @@ impl<A: GlobalAlloc> Mimalloc<A> {
         self.deferred_free_hook = Some(hook);
     }
 
+    /// Register an allocator to deallocate pointers that are not allocated by this crate,
+    /// e.g. memory allocated by [`System`](std::alloc::System) before switching the global
+    /// allocator. A new fallback replaces the old one.
+    ///
+    /// Without a fallback, such pointers are reported as
+    /// [`Error::InvalidFree`](error::Error::InvalidFree) and ignored.
+    #[cfg(feature = "segment_map")]
+    pub const fn register_fallback(&mut self, fallback: &'static (dyn GlobalAlloc + Sync)) {
+        self.fallback = Some(fallback);
+    }
+
     /// Collect free memory.
     pub fn collect(&mut self) {
         self.heap.collect(&self.os_alloc);
@@ -104,6 +242,26 @@ impl<A: GlobalAlloc> Mimalloc<A> {
         self.heap.malloc_aligned(
             layout.size(),
             layout.align(),
+            false,
+            &self.os_alloc,
+            #[cfg(feature = "deferred_free")]
+            self.deferred_free_hook,
+        )
+    }
+
+    /// [`GlobalAlloc::alloc_zeroed`] but requires a mutable reference `&mut self`.
+    ///
+    /// Blocks that have never been used are not cleared again if the OS allocator returns
+    /// zeroed memory, see [`with_zeroed_os_allocator`](Self::with_zeroed_os_allocator).
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::alloc_zeroed`].
+    pub unsafe fn alloc_zeroed(&mut self, layout: Layout) -> *mut u8 {
+        self.heap.malloc_aligned(
+            layout.size(),
+            layout.align(),
+            true,
             &self.os_alloc,
             #[cfg(feature = "deferred_free")]
             self.deferred_free_hook,
@@ -115,17 +273,87 @@ impl<A: GlobalAlloc> Mimalloc<A> {
     /// # Safety
     ///
     /// See [`GlobalAlloc::dealloc`].
-    pub unsafe fn dealloc(&mut self, ptr: *mut u8, _: Layout) {
+    #[cfg_attr(not(feature = "segment_map"), allow(unused_variables))]
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "segment_map")]
+        if let Some(fallback) = self.fallback.filter(|_| segment_map::is_foreign(ptr)) {
+            fallback.dealloc(ptr, layout);
+            return;
+        }
         self.heap.free(ptr, &self.os_alloc)
     }
+
+    /// The number of bytes that can be used in the allocation at `ptr`, which is at least the
+    /// requested size, or 0 if `ptr` is null.
+    ///
+    /// With the `padding` feature, it is exactly the requested size, since the rest of the block
+    /// is checked for overflows. With the `segment_map` feature, it is also 0 for pointers not
+    /// allocated by this crate.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null, or allocated by this allocator and not deallocated yet.
+    /// With the `segment_map` feature, it can also be a pointer not allocated by this crate.
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        Heap::usable_size(ptr)
+    }
+
+    /// [`GlobalAlloc::realloc`] but requires a mutable reference `&mut self`.
+    ///
+    /// The block is resized in place if `new_size` still fits in it and does not waste more
+    /// than half of it. Otherwise, it is moved to a new block.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.realloc_zero(ptr, layout, new_size, false)
+    }
+
+    /// [`realloc`](Self::realloc) that also zeroes the bytes after the old size when growing.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        self.realloc_zero(ptr, layout, new_size, true)
+    }
+
+    unsafe fn realloc_zero(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+        zero: bool,
+    ) -> *mut u8 {
+        if realloc::try_in_place(ptr, layout, new_size, zero) {
+            return ptr;
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            realloc::copy(ptr, layout.size(), new_ptr, new_size, zero);
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
 
 impl<A: GlobalAlloc> Drop for Mimalloc<A> {
     fn drop(&mut self) {
+        #[cfg(feature = "guarded")]
+        self.heap.release_guarded_quarantine(&self.os_alloc);
         self.collect();
     }
 }
 
+mod cell;
+pub use cell::MimallocCell;
+
 #[cfg(feature = "mmap")]
 mod mmap;
 #[cfg(feature = "mmap")]
@@ -134,10 +362,27 @@ pub use mmap::{new_mimalloc_mmap, MimallocMmap, MmapAlloc};
 #[cfg(all(not(docsrs), feature = "std_mutex", feature = "spin_mutex"))]
 compile_error!("Only one of 'std_mutex' and 'spin_mutex' features can be enabled");
 
-#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+#[cfg(all(not(docsrs), feature = "zero_on_free", feature = "fill_on_free"))]
+compile_error!("Only one of 'zero_on_free' and 'fill_on_free' features can be enabled");
+
+#[cfg(all(
+    feature = "std",
+    any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api")
+))]
+mod emergency;
+#[cfg(all(
+    feature = "mmap",
+    any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api")
+))]
+mod fork;
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api"))]
 mod mutex;
+#[cfg(feature = "lock_api")]
+pub use mutex::MimallocRawMutexWrapper;
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api"))]
+pub use mutex::{AllocatorMutex, MimallocLockWrapper};
 #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
-pub use mutex::MimallocMutexWrapper;
+pub use mutex::{MimallocMutexWrapper, MimallocShardedWrapper};
 
 #[cfg(all(feature = "mmap", any(feature = "std_mutex", feature = "spin_mutex")))]
 /// Wrapper around [`Mimalloc`] with `mmap` allocator and mutex.
@@ -145,5 +390,35 @@ pub type MimallocMmapMutex = MimallocMutexWrapper<MmapAlloc>;
 #[cfg(all(feature = "mmap", any(feature = "std_mutex", feature = "spin_mutex")))]
 /// Create a new [`MimallocMmapMutex`] instance by a `const fn`.
 pub const fn new_mimalloc_mmap_mutex() -> MimallocMmapMutex {
-    MimallocMutexWrapper::with_os_allocator(MmapAlloc)
+    MimallocMutexWrapper::with_zeroed_os_allocator(MmapAlloc)
+}
+
+#[cfg(feature = "critical_section")]
+mod critical;
+#[cfg(feature = "critical_section")]
+pub use critical::MimallocCriticalSection;
+
+#[cfg(all(
+    feature = "thread_cache",
+    any(feature = "std_mutex", feature = "spin_mutex")
+))]
+mod thread_cache;
+#[cfg(all(
+    feature = "thread_cache",
+    any(feature = "std_mutex", feature = "spin_mutex")
+))]
+pub use thread_cache::MimallocCacheWrapper;
+
+#[cfg(feature = "thread_local")]
+mod thread_local;
+#[cfg(feature = "thread_local")]
+pub use thread_local::MimallocThreadLocal;
+
+#[cfg(all(feature = "mmap", feature = "thread_local"))]
+/// Thread-local [`Mimalloc`] heaps with `mmap` allocator.
+pub type MimallocMmapThreadLocal = MimallocThreadLocal<MmapAlloc>;
+#[cfg(all(feature = "mmap", feature = "thread_local"))]
+/// Create a new [`MimallocMmapThreadLocal`] instance by a `const fn`.
+pub const fn new_mimalloc_mmap_thread_local() -> MimallocMmapThreadLocal {
+    MimallocThreadLocal::with_zeroed_os_allocator(MmapAlloc)
 }
@@ -87,7 +87,7 @@ impl<T: LinkedListItem> LinkedList<T> {
 
     /// Check if an element is in the list. The element must not be in another list.
     pub fn contains(&self, el: &T) -> bool {
-        !el.next().is_null() || !el.prev().is_null() || el as *const _ == self.first
+        !el.next().is_null() || !el.prev().is_null() || core::ptr::eq(el, self.first)
     }
 }
 
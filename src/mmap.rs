@@ -1,14 +1,22 @@
+#[cfg(feature = "random_mmap")]
+use crate::constants::MI_SEGMENT_SIZE;
+#[cfg(feature = "random_mmap")]
+use crate::random::Random;
 use crate::Mimalloc;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ffi::c_void;
 use core::ptr::null_mut;
+#[cfg(feature = "random_mmap")]
+use core::sync::atomic::{AtomicU64, Ordering};
 use libc::{mmap, munmap, sysconf};
-use libc::{MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE, _SC_PAGE_SIZE};
+#[cfg(feature = "guard_pages")]
+use libc::{mprotect, PROT_NONE};
+use libc::{_SC_PAGE_SIZE, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
 
 /// A simple `mmap`-based allocator that can be used to power [`Mimalloc`].
 ///
 /// It is only used to allocate large chunks of memory and is not suitable for general malloc.
-#[derive(Default)]
+#[derive(Clone, Copy, Default)]
 pub struct MmapAlloc;
 
 /// [`Mimalloc`] powered by `mmap` ([`MmapAlloc`]).
@@ -16,12 +24,16 @@ pub type MimallocMmap = Mimalloc<MmapAlloc>;
 
 /// Create a new [`MimallocMmap`] instance by a `const fn`.
 pub const fn new_mimalloc_mmap() -> MimallocMmap {
-    Mimalloc::with_os_allocator(MmapAlloc)
+    Mimalloc::with_zeroed_os_allocator(MmapAlloc)
 }
 
 unsafe fn mmap_anoymous(size: usize) -> *mut c_void {
+    mmap_anoymous_at(null_mut(), size)
+}
+
+unsafe fn mmap_anoymous_at(hint: *mut c_void, size: usize) -> *mut c_void {
     mmap(
-        null_mut(),
+        hint,
         size,
         PROT_READ | PROT_WRITE,
         MAP_PRIVATE | MAP_ANONYMOUS,
@@ -30,6 +42,41 @@ unsafe fn mmap_anoymous(size: usize) -> *mut c_void {
     )
 }
 
+/// Start of the address range of the hints.
+#[cfg(all(feature = "random_mmap", target_pointer_width = "64"))]
+const MI_HINT_BASE: usize = 2 << 40; // 2TiB
+/// End of the address range of the hints.
+#[cfg(all(feature = "random_mmap", target_pointer_width = "64"))]
+const MI_HINT_MAX: usize = 30 << 40; // 30TiB
+
+// _mi_os_get_aligned_hint
+/// A random address aligned to `align` and [`MI_SEGMENT_SIZE`] to map `size` bytes at, or null
+/// if there is no room for randomization, i.e. on 32-bit platforms.
+#[cfg(feature = "random_mmap")]
+fn random_hint(size: usize, align: usize) -> *mut c_void {
+    #[cfg(target_pointer_width = "64")]
+    {
+        static STATE: AtomicU64 = AtomicU64::new(0);
+
+        let align = align.max(MI_SEGMENT_SIZE);
+        if size > (MI_HINT_MAX - MI_HINT_BASE) / 2 || align > MI_HINT_BASE {
+            return null_mut();
+        }
+        if STATE.load(Ordering::Relaxed) == 0 {
+            let seed = Random::new().next() as u64 | 1;
+            let _ = STATE.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        let random = Random::with_seed(STATE.fetch_add(1, Ordering::Relaxed)).next();
+        let slots = (MI_HINT_MAX - MI_HINT_BASE - size) / align;
+        (MI_HINT_BASE + random % slots * align) as _
+    }
+    #[cfg(not(target_pointer_width = "64"))]
+    {
+        let _ = (size, align);
+        null_mut()
+    }
+}
+
 unsafe impl GlobalAlloc for MmapAlloc {
     /// See [`GlobalAlloc::alloc`].
     ///
@@ -41,8 +88,23 @@ unsafe impl GlobalAlloc for MmapAlloc {
         let align = layout.align();
 
         // `mmap` and `munmap` requires addresses to be aligned to page size
-        debug_assert!(size % sysconf(_SC_PAGE_SIZE) as usize == 0);
-        debug_assert!(align % sysconf(_SC_PAGE_SIZE) as usize == 0);
+        debug_assert!(size.is_multiple_of(sysconf(_SC_PAGE_SIZE) as usize));
+        debug_assert!(align.is_multiple_of(sysconf(_SC_PAGE_SIZE) as usize));
+
+        #[cfg(feature = "random_mmap")]
+        {
+            let hint = random_hint(size, align);
+            if !hint.is_null() {
+                let p = mmap_anoymous_at(hint, size);
+                if p == hint {
+                    return p.cast();
+                }
+                // the hint collides with an existing mapping
+                if p != MAP_FAILED {
+                    munmap(p, size);
+                }
+            }
+        }
 
         // try mapping exactly `size` at first
         let p = mmap_anoymous(size);
@@ -50,7 +112,7 @@ unsafe impl GlobalAlloc for MmapAlloc {
             return null_mut();
         }
 
-        if p as usize % align == 0 {
+        if (p as usize).is_multiple_of(align) {
             // aligned
             return p.cast();
         }
@@ -79,3 +141,17 @@ unsafe impl GlobalAlloc for MmapAlloc {
         munmap(ptr.cast(), layout.size());
     }
 }
+
+// anonymous mappings are zero-filled
+unsafe impl crate::ZeroedAlloc for MmapAlloc {}
+
+#[cfg(feature = "guard_pages")]
+unsafe impl crate::ProtectAlloc for MmapAlloc {
+    fn page_size(&self) -> usize {
+        unsafe { sysconf(_SC_PAGE_SIZE) as usize }
+    }
+
+    unsafe fn protect(&self, ptr: *mut u8, size: usize) {
+        mprotect(ptr.cast(), size, PROT_NONE);
+    }
+}
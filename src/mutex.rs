@@ -1,50 +1,653 @@
+use crate::heap::Heap;
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use crate::heap::HeapOptions;
+use crate::page::Block;
+use crate::realloc;
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use crate::segment::Segment;
+#[cfg(feature = "segment_map")]
+use crate::segment_map;
 use crate::Mimalloc;
 use core::alloc::{GlobalAlloc, Layout};
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+#[cfg(feature = "std")]
+use {
+    crate::emergency,
+    crate::error::{report, Error},
+    core::sync::atomic::AtomicUsize,
+};
 
 #[cfg(feature = "spin_mutex")]
-use spin::{Mutex, MutexGuard};
-#[cfg(not(feature = "spin_mutex"))]
-use std::sync::{Mutex, MutexGuard};
+use spin::Mutex;
+#[cfg(feature = "std_mutex")]
+use std::sync::{Mutex, MutexGuard, TryLockError};
 
-/// Wrap [`Mimalloc`] inside a [`Mutex`] and implement [`GlobalAlloc`].
+/// A mutex that protects [`Mimalloc`] in [`MimallocLockWrapper`].
+pub trait AllocatorMutex {
+    /// The OS allocator of the protected [`Mimalloc`].
+    type OsAlloc: GlobalAlloc;
+
+    /// Guard that releases the lock when dropped.
+    type Guard<'a>: DerefMut<Target = Mimalloc<Self::OsAlloc>>
+    where
+        Self: 'a;
+
+    /// Acquire the lock, blocking until it is available.
+    fn lock(&self) -> Self::Guard<'_>;
+
+    /// Acquire the lock if it is available, or return [`None`] without blocking.
+    fn try_lock(&self) -> Option<Self::Guard<'_>>;
+}
+
+#[cfg(feature = "spin_mutex")]
+impl<A: GlobalAlloc> AllocatorMutex for Mutex<Mimalloc<A>> {
+    type OsAlloc = A;
+
+    type Guard<'a>
+        = spin::MutexGuard<'a, Mimalloc<A>>
+    where
+        A: 'a;
+
+    fn lock(&self) -> Self::Guard<'_> {
+        self.lock()
+    }
+
+    fn try_lock(&self) -> Option<Self::Guard<'_>> {
+        self.try_lock()
+    }
+}
+
+#[cfg(feature = "std_mutex")]
+impl<A: GlobalAlloc> AllocatorMutex for Mutex<Mimalloc<A>> {
+    type OsAlloc = A;
+
+    type Guard<'a>
+        = MutexGuard<'a, Mimalloc<A>>
+    where
+        A: 'a;
+
+    fn lock(&self) -> Self::Guard<'_> {
+        self.lock().expect("failed to lock the allocator")
+    }
+
+    fn try_lock(&self) -> Option<Self::Guard<'_>> {
+        match self.try_lock() {
+            Ok(allocator) => Some(allocator),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => None,
+        }
+    }
+}
+
+#[cfg(feature = "lock_api")]
+impl<R: lock_api::RawMutex, A: GlobalAlloc> AllocatorMutex for lock_api::Mutex<R, Mimalloc<A>> {
+    type OsAlloc = A;
+
+    type Guard<'a>
+        = lock_api::MutexGuard<'a, R, Mimalloc<A>>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> Self::Guard<'_> {
+        self.lock()
+    }
+
+    fn try_lock(&self) -> Option<Self::Guard<'_>> {
+        self.try_lock()
+    }
+}
+
+/// Wrap [`Mimalloc`] inside a mutex `M` and implement [`GlobalAlloc`].
+///
+/// Deallocation does not take the lock. Freed blocks are pushed to atomic lists
+/// and collected by the lock holder when it needs more memory. Huge blocks are freed
+/// right away if the lock is available, otherwise they are returned to the OS allocator
+/// when the lock is taken next time.
+///
+/// With the `std` feature, allocating again on the thread holding the lock (e.g. from a deferred
+/// free hook or from the OS allocator) is reported as [`Error::Reentrant`](crate::error::Error)
+/// instead of deadlocking, and the nested allocation is served from a small emergency region.
+/// Without `std`, the lock holder cannot be identified, so reentrant allocations deadlock. Code
+/// that may run while the lock is held should allocate with [`try_alloc`](Self::try_alloc),
+/// which returns null instead.
+///
+/// See [`MimallocMutexWrapper`] and [`MimallocRawMutexWrapper`].
 #[derive(Default)]
-pub struct MimallocMutexWrapper<A: GlobalAlloc>(Mutex<Mimalloc<A>>);
+pub struct MimallocLockWrapper<M: AllocatorMutex> {
+    allocator: M,
+    /// Blocks freed in full pages.
+    delayed_free: AtomicPtr<Block>,
+    /// A huge block is freed without the lock.
+    huge_freed: AtomicBool,
+    /// The [`thread_id`] of the lock holder, or 0.
+    #[cfg(feature = "std")]
+    owner: AtomicUsize,
+}
+
+/// [`MimallocLockWrapper`] with the [`Mutex`] chosen by the `std_mutex` or `spin_mutex` feature.
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+pub type MimallocMutexWrapper<A> = MimallocLockWrapper<Mutex<Mimalloc<A>>>;
+
+/// [`MimallocLockWrapper`] with a [`lock_api::Mutex`] of any [`RawMutex`](lock_api::RawMutex) `R`.
+#[cfg(feature = "lock_api")]
+pub type MimallocRawMutexWrapper<R, A> = MimallocLockWrapper<lock_api::Mutex<R, Mimalloc<A>>>;
 
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
 impl<A: GlobalAlloc> MimallocMutexWrapper<A> {
     /// See [`Mimalloc::with_os_allocator`].
     pub const fn with_os_allocator(os_alloc: A) -> Self {
-        Self(Mutex::new(Mimalloc::with_os_allocator(os_alloc)))
+        Self::with_allocator(Mimalloc::with_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_zeroed_os_allocator`].
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self::with_allocator(Mimalloc::with_zeroed_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_guard_pages`].
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self::with_allocator(Mimalloc::with_guard_pages(os_alloc))
+    }
+
+    const fn with_allocator(allocator: Mimalloc<A>) -> Self {
+        Self::with_mutex(Mutex::new(allocator))
+    }
+}
+
+#[cfg(feature = "lock_api")]
+impl<R: lock_api::RawMutex, A: GlobalAlloc> MimallocRawMutexWrapper<R, A> {
+    /// See [`Mimalloc::with_os_allocator`].
+    pub const fn with_os_allocator(os_alloc: A) -> Self {
+        Self::with_mutex(lock_api::Mutex::new(Mimalloc::with_os_allocator(os_alloc)))
+    }
+
+    /// See [`Mimalloc::with_zeroed_os_allocator`].
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self::with_mutex(lock_api::Mutex::new(Mimalloc::with_zeroed_os_allocator(
+            os_alloc,
+        )))
+    }
+
+    /// See [`Mimalloc::with_guard_pages`].
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self::with_mutex(lock_api::Mutex::new(Mimalloc::with_guard_pages(os_alloc)))
+    }
+}
+
+impl<M: AllocatorMutex> MimallocLockWrapper<M> {
+    const fn with_mutex(allocator: M) -> Self {
+        Self {
+            allocator,
+            delayed_free: AtomicPtr::new(null_mut()),
+            huge_freed: AtomicBool::new(false),
+            #[cfg(feature = "std")]
+            owner: AtomicUsize::new(0),
+        }
     }
 
     #[cfg(feature = "deferred_free")]
     /// See [`Mimalloc::register_deferred_free`].
-    pub fn register_deferred_free(&self, hook: crate::DeferredFreeHook<A>) {
-        self.allocator().register_deferred_free(hook);
+    pub fn register_deferred_free(&self, hook: crate::DeferredFreeHook<M::OsAlloc>) {
+        if let Some(mut allocator) = self.allocator() {
+            allocator.register_deferred_free(hook);
+        }
+    }
+
+    #[cfg(feature = "segment_map")]
+    /// See [`Mimalloc::register_fallback`].
+    pub fn register_fallback(&self, fallback: &'static (dyn GlobalAlloc + Sync)) {
+        if let Some(mut allocator) = self.allocator() {
+            allocator.register_fallback(fallback);
+        }
+    }
+
+    #[cfg(feature = "guarded")]
+    /// See [`Mimalloc::set_guarded_sample_rate`].
+    pub fn set_guarded_sample_rate(&self, rate: usize) {
+        if let Some(mut allocator) = self.allocator() {
+            allocator.set_guarded_sample_rate(rate);
+        }
     }
 
     /// See [`Mimalloc::collect`].
     pub fn collect(&self) {
-        self.allocator().collect();
+        if let Some(mut allocator) = self.allocator() {
+            allocator.collect();
+        }
     }
 
-    fn allocator(&self) -> MutexGuard<Mimalloc<A>> {
-        #[cfg(feature = "spin_mutex")]
-        {
-            self.0.lock()
+    #[cfg(feature = "mmap")]
+    /// Register `pthread_atfork` handlers that acquire the lock before `fork` and release it
+    /// afterwards in both the parent and the child, so that the child does not inherit
+    /// a lock held by another thread.
+    ///
+    /// A block that another thread was freeing during `fork` may leak in the child.
+    ///
+    /// Registering the same allocator again has no effect. Returns `false` if the handlers
+    /// cannot be registered. At most 64 allocators can be registered.
+    pub fn register_fork_handlers(&'static self) -> bool
+    where
+        M: Sync + 'static,
+    {
+        crate::fork::register(self)
+    }
+
+    /// [`GlobalAlloc::alloc`] but returns null instead of blocking if the lock is held.
+    ///
+    /// It does not block or spin, so it can be called from signal handlers
+    /// if the OS allocator can (e.g. [`MmapAlloc`](crate::MmapAlloc)).
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::alloc`].
+    pub unsafe fn try_alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_allocator()
+            .map_or(null_mut(), |mut allocator| allocator.alloc(layout))
+    }
+
+    /// [`GlobalAlloc::dealloc`] that never blocks.
+    ///
+    /// The block is pushed to a lock-free list, and the next lock holder collects it.
+    /// A huge block is freed directly if the lock is available.
+    /// It can be called from signal handlers.
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::dealloc`].
+    pub unsafe fn try_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "std")]
+        if emergency::contains(ptr) {
+            return;
+        }
+        if !Heap::is_huge(ptr) {
+            Heap::free_remote(ptr, |_| &self.delayed_free);
+        } else if let Some(mut allocator) = self.try_allocator() {
+            allocator.dealloc(ptr, layout);
+        } else {
+            Heap::free_remote(ptr, |_| &self.delayed_free);
+            self.huge_freed.store(true, Ordering::Release);
         }
-        #[cfg(not(feature = "spin_mutex"))]
+    }
+
+    /// See [`Mimalloc::realloc_zeroed`].
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, true)
+    }
+
+    /// See [`Mimalloc::usable_size`]. It does not take the lock, and returns 0 for
+    /// allocations from the emergency region.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mimalloc::usable_size`].
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        #[cfg(feature = "std")]
+        if emergency::contains(ptr) {
+            return 0;
+        }
+        Heap::usable_size(ptr)
+    }
+
+    /// Forward `ptr` to the fallback allocator if it is not allocated by this crate.
+    /// Returns whether it is forwarded.
+    ///
+    /// Foreign pointers are rare, so it locks the allocator to access the fallback.
+    #[cfg(feature = "segment_map")]
+    unsafe fn dealloc_foreign(&self, ptr: *mut u8, layout: Layout) -> bool {
+        #[cfg(feature = "std")]
+        if emergency::contains(ptr) {
+            return false;
+        }
+        if !segment_map::is_foreign(ptr) {
+            return false;
+        }
+        if let Some(mut allocator) = self.allocator() {
+            allocator.dealloc(ptr, layout);
+        }
+        true
+    }
+
+    /// Lock the allocator and free the blocks in full pages.
+    ///
+    /// Returns [`None`] and reports [`Error::Reentrant`] if the current thread is already
+    /// holding the lock.
+    pub(crate) fn allocator(&self) -> Option<AllocatorGuard<'_, M>> {
+        #[cfg(feature = "std")]
+        if self.owner.load(Ordering::Relaxed) == thread_id() {
+            report(Error::Reentrant);
+            return None;
+        }
+        Some(self.delayed_free_collect(self.allocator.lock()))
+    }
+
+    /// [`Self::allocator`] but returns [`None`] instead of blocking.
+    fn try_allocator(&self) -> Option<AllocatorGuard<'_, M>> {
+        Some(self.delayed_free_collect(self.allocator.try_lock()?))
+    }
+
+    fn delayed_free_collect<'a>(&'a self, guard: M::Guard<'a>) -> AllocatorGuard<'a, M> {
+        #[cfg(feature = "std")]
+        self.owner.store(thread_id(), Ordering::Relaxed);
+        let mut allocator = AllocatorGuard {
+            guard,
+            #[cfg(feature = "std")]
+            owner: &self.owner,
+        };
+        let Mimalloc { heap, os_alloc, .. } = &mut *allocator;
+        heap.delayed_free_collect(&self.delayed_free, os_alloc);
+        if self.huge_freed.load(Ordering::Relaxed) && self.huge_freed.swap(false, Ordering::Acquire)
         {
-            self.0.lock().expect("failed to lock the allocator")
+            heap.collect_huge(os_alloc);
+        }
+        allocator
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<M: AllocatorMutex + Sync + 'static> crate::fork::ForkLock for MimallocLockWrapper<M> {
+    type Guard = M::Guard<'static>;
+
+    fn lock(&'static self) -> Self::Guard {
+        self.allocator.lock()
+    }
+
+    fn child(guard: &mut Self::Guard) {
+        // a thread may have been freeing into a full page when it forked
+        #[cfg(atomic_free)]
+        guard.heap.reset_delayed_freeing();
+    }
+}
+
+/// Guard of the locked allocator that records the lock holder.
+pub(crate) struct AllocatorGuard<'a, M: AllocatorMutex + 'a> {
+    guard: M::Guard<'a>,
+    #[cfg(feature = "std")]
+    owner: &'a AtomicUsize,
+}
+
+impl<M: AllocatorMutex> Deref for AllocatorGuard<'_, M> {
+    type Target = Mimalloc<M::OsAlloc>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<M: AllocatorMutex> DerefMut for AllocatorGuard<'_, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, M: AllocatorMutex + 'a> Drop for AllocatorGuard<'a, M> {
+    fn drop(&mut self) {
+        // cleared before `guard` is dropped and the lock is released
+        self.owner.store(0, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<M: AllocatorMutex> GlobalAlloc for MimallocLockWrapper<M> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.allocator() {
+            Some(mut allocator) => allocator.alloc(layout),
+            #[cfg(feature = "std")]
+            None => emergency::alloc(layout),
+            #[cfg(not(feature = "std"))]
+            None => null_mut(),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match self.allocator() {
+            Some(mut allocator) => allocator.alloc_zeroed(layout),
+            // the emergency region is never reused, so it is still zero
+            #[cfg(feature = "std")]
+            None => emergency::alloc(layout),
+            #[cfg(not(feature = "std"))]
+            None => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "segment_map")]
+        if self.dealloc_foreign(ptr, layout) {
+            return;
+        }
+        self.try_dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, false)
+    }
+}
+
+impl<M: AllocatorMutex> Drop for MimallocLockWrapper<M> {
+    fn drop(&mut self) {
+        // free the delayed blocks before `Mimalloc` collects
+        drop(self.allocator());
+    }
+}
+
+/// Spread threads across `N` independently locked [`Mimalloc`] instances
+/// and implement [`GlobalAlloc`].
+///
+/// Each thread prefers one shard, and tries the other shards if it is locked.
+/// Deallocation does not take any lock, and freed blocks are returned to the shard that owns
+/// the segment.
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+pub struct MimallocShardedWrapper<A: GlobalAlloc, const N: usize> {
+    shards: [MimallocMutexWrapper<A>; N],
+}
+
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+impl<A: GlobalAlloc + Copy, const N: usize> MimallocShardedWrapper<A, N> {
+    /// Create a new [`MimallocShardedWrapper`] instance with an OS allocator
+    /// shared by all shards.
+    pub const fn with_os_allocator(os_alloc: A) -> Self {
+        Self::with_options(os_alloc, HeapOptions::new())
+    }
+
+    /// [`with_os_allocator`](Self::with_os_allocator) but the OS allocator returns zeroed
+    /// memory. See [`Mimalloc::with_zeroed_os_allocator`].
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self::with_options(os_alloc, HeapOptions::new().with_os_zero())
+    }
+
+    /// [`with_os_allocator`](Self::with_os_allocator) but the OS allocator places guard pages.
+    /// See [`Mimalloc::with_guard_pages`].
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self::with_options(os_alloc, HeapOptions::new().with_guard_pages::<A>())
+    }
+
+    const fn with_options(os_alloc: A, options: HeapOptions) -> Self {
+        assert!(N > 0, "there must be at least one shard");
+        let mut shards = [const { MaybeUninit::uninit() }; N];
+        let mut i = 0;
+        while i < N {
+            let mut allocator = Mimalloc::with_os_allocator(os_alloc);
+            allocator.heap.set_options(options);
+            allocator.heap.set_shard(i);
+            shards[i] = MaybeUninit::new(MimallocMutexWrapper::with_allocator(allocator));
+            i += 1;
         }
+        // all shards are initialized
+        let shards = unsafe { (&raw const shards as *const [MimallocMutexWrapper<A>; N]).read() };
+        Self { shards }
     }
 }
 
-unsafe impl<A: GlobalAlloc> GlobalAlloc for MimallocMutexWrapper<A> {
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+impl<A: GlobalAlloc, const N: usize> MimallocShardedWrapper<A, N> {
+    #[cfg(feature = "deferred_free")]
+    /// Register the hook for all shards. See [`Mimalloc::register_deferred_free`].
+    pub fn register_deferred_free(&self, hook: crate::DeferredFreeHook<A>) {
+        for shard in &self.shards {
+            shard.register_deferred_free(hook);
+        }
+    }
+
+    #[cfg(feature = "segment_map")]
+    /// Register the fallback for all shards. See [`Mimalloc::register_fallback`].
+    pub fn register_fallback(&self, fallback: &'static (dyn GlobalAlloc + Sync)) {
+        for shard in &self.shards {
+            shard.register_fallback(fallback);
+        }
+    }
+
+    #[cfg(feature = "guarded")]
+    /// Set the rate for all shards. See [`Mimalloc::set_guarded_sample_rate`].
+    pub fn set_guarded_sample_rate(&self, rate: usize) {
+        for shard in &self.shards {
+            shard.set_guarded_sample_rate(rate);
+        }
+    }
+
+    /// Collect free memory of all shards. See [`Mimalloc::collect`].
+    pub fn collect(&self) {
+        for shard in &self.shards {
+            shard.collect();
+        }
+    }
+
+    /// See [`Mimalloc::usable_size`]. It does not take any lock.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mimalloc::usable_size`].
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        self.shards[0].usable_size(ptr)
+    }
+
+    #[cfg(feature = "mmap")]
+    /// Register the fork handlers for all shards.
+    /// See [`MimallocMutexWrapper::register_fork_handlers`].
+    ///
+    /// Each shard counts as one allocator.
+    pub fn register_fork_handlers(&'static self) -> bool
+    where
+        A: 'static,
+    {
+        self.shards
+            .iter()
+            .all(MimallocMutexWrapper::register_fork_handlers)
+    }
+
+    /// See [`Mimalloc::realloc_zeroed`].
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, true)
+    }
+}
+
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+unsafe impl<A: GlobalAlloc, const N: usize> GlobalAlloc for MimallocShardedWrapper<A, N> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.allocator().alloc(layout)
+        let preferred = thread_index() % N;
+        for i in 0..N {
+            if let Some(mut allocator) = self.shards[(preferred + i) % N].try_allocator() {
+                return allocator.alloc(layout);
+            }
+        }
+        self.shards[preferred].alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let preferred = thread_index() % N;
+        for i in 0..N {
+            if let Some(mut allocator) = self.shards[(preferred + i) % N].try_allocator() {
+                return allocator.alloc_zeroed(layout);
+            }
+        }
+        self.shards[preferred].alloc_zeroed(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.allocator().dealloc(ptr, layout)
+        #[cfg(feature = "segment_map")]
+        if self.shards[thread_index() % N].dealloc_foreign(ptr, layout) {
+            return;
+        }
+        #[cfg(feature = "std")]
+        if emergency::contains(ptr) {
+            return;
+        }
+        // the owning shard frees huge blocks directly if it is not locked
+        if let Some(segment) = Segment::of_allocated_ptr(ptr) {
+            self.shards[segment.shard()].try_dealloc(ptr, layout);
+        }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, false)
+    }
+}
+
+/// A nonzero identifier that is unique among the running threads.
+#[cfg(feature = "std")]
+fn thread_id() -> usize {
+    std::thread_local! {
+        static ID: u8 = const { 0 };
+    }
+    ID.with(|id| id as *const u8 as usize)
+}
+
+/// An index that is different for each thread, or at least for most threads.
+#[cfg(all(feature = "std", any(feature = "std_mutex", feature = "spin_mutex")))]
+fn thread_index() -> usize {
+    use core::cell::Cell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+    std::thread_local! {
+        static INDEX: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    INDEX.with(|index| {
+        index.get().unwrap_or_else(|| {
+            let new_index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+            index.set(Some(new_index));
+            new_index
+        })
+    })
+}
+
+/// An index that is different for each thread, or at least for most threads.
+#[cfg(all(not(feature = "std"), feature = "spin_mutex"))]
+fn thread_index() -> usize {
+    // threads run on different stacks that are usually at least 1 MiB apart
+    let local = 0u8;
+    (&raw const local as usize) >> 20
 }
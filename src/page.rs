@@ -1,14 +1,20 @@
 // NOTE: Avoid using `ptr::{add, offset_from}` when unsafe (UB). Convert to usize instead.
 
 use crate::constants::*;
+#[cfg(any(feature = "secure", feature = "guarded"))]
+use crate::error::{report, Error};
 use crate::heap::Heap;
 use crate::list::impl_list_item;
+#[cfg(feature = "secure")]
+use crate::random::Random;
 use crate::segment::Segment;
 use crate::utils::bin_for_size;
 #[cfg(feature = "deferred_free")]
 use crate::DeferredFreeHook;
 use core::alloc::GlobalAlloc;
 use core::ptr::{null_mut, NonNull};
+#[cfg(atomic_free)]
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 #[repr(align(2))]
 #[derive(Clone, Copy)]
@@ -24,14 +30,24 @@ union PageFlagUnion {
 
 pub struct Page {
     in_use: bool,
+    /// The memory of the page is zeroed by the OS allocator and has not been used before.
+    is_zero: bool,
+    /// The blocks in `free` are zero except for the link.
+    free_is_zero: bool,
     flags: PageFlagUnion, // save a branch in `free_block`
     capacity: u16,
     reserved: u16,
     free: *mut Block,
     used: u16,
     local_free: *mut Block,
+    /// Blocks freed by other threads, tagged with the delayed free state in the low bits.
+    #[cfg(atomic_free)]
+    thread_free: AtomicUsize,
     block_size: usize,
     bin: u8,
+    /// Keys to encode the free list pointers.
+    #[cfg(feature = "secure")]
+    keys: [usize; 2],
     next: *mut Self,
     prev: *mut Self,
 }
@@ -39,9 +55,53 @@ pub struct Page {
 impl_list_item!(Page);
 
 pub struct Block {
+    /// Encoded with the keys of the page containing this block.
     next: *mut Self,
 }
 
+/// The byte to fill freed blocks with.
+#[cfg(feature = "zero_on_free")]
+const FREE_FILL: u8 = 0;
+/// The byte to fill freed blocks with (`MI_DEBUG_FREED`).
+#[cfg(feature = "fill_on_free")]
+const FREE_FILL: u8 = 0xDF;
+
+/// Fill a free block of `block_size` bytes with [`FREE_FILL`], except the `next` word.
+#[cfg(any(feature = "zero_on_free", feature = "fill_on_free"))]
+pub fn fill_free(block: *mut u8, block_size: usize) {
+    let start = block as usize + size_of::<Block>();
+    unsafe { (start as *mut u8).write_bytes(FREE_FILL, block_size - size_of::<Block>()) };
+}
+
+/// Check that a free block of `block_size` bytes is not written after it is freed.
+#[cfg(all(feature = "fill_on_free", debug_assertions))]
+pub fn check_free_fill(block: *const u8, block_size: usize) {
+    let start = block as usize + size_of::<Block>();
+    let size = block_size - size_of::<Block>();
+    let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, size) };
+    debug_assert!(
+        bytes.iter().all(|&byte| byte == FREE_FILL),
+        "block written after free: {block:p}, block size {block_size}",
+    );
+}
+
+/* delayed free states stored in the low bits of `Page::thread_free` */
+
+/// Push to `thread_free` as usual.
+#[cfg(atomic_free)]
+const NO_DELAYED_FREE: usize = 0;
+/// The page is full, push to the delayed free list of the owner instead.
+#[cfg(atomic_free)]
+const USE_DELAYED_FREE: usize = 1;
+/// A thread is pushing to the delayed free list of the owner.
+#[cfg(atomic_free)]
+const DELAYED_FREEING: usize = 2;
+/// The page is abandoned, always push to `thread_free`.
+#[cfg(feature = "thread_local")]
+const NEVER_DELAYED_FREE: usize = 3;
+#[cfg(atomic_free)]
+const DELAYED_MASK: usize = 3;
+
 impl Page {
     pub fn malloc_fast<'a, A: GlobalAlloc>(
         mut page: NonNull<Self>,
@@ -69,27 +129,36 @@ impl Page {
                         < MI_SEGMENT_SIZE,
                     "block not in segment: block {block:p}, page {page:p}",
                 );
+                let next = unsafe { page.as_ref() }.next_block(block);
                 debug_assert!(
-                    block.next.is_null() ||
-                    (block.next as usize).abs_diff(block as *const _ as usize) % unsafe { page.as_ref() }.block_size == 0,
-                    "diff between block and next not multiple of block size: block {block:p}, next {:p}, block size {}",
-                    block.next,
+                    next.is_null() ||
+                    (next as usize).abs_diff(block as *const _ as usize).is_multiple_of(unsafe { page.as_ref() }.block_size),
+                    "diff between block and next not multiple of block size: block {block:p}, next {next:p}, block size {}",
                     unsafe{page.as_ref()}.block_size
                 );
                 debug_assert!(
-                    if block.next.is_null() {
+                    if next.is_null() {
                         true
                     } else {
                         let segment = Segment::of_ptr(block);
                         let segment = unsafe { segment.as_ref() }.unwrap();
-                        (block.next as usize).abs_diff(block as *const _ as usize)
+                        (next as usize).abs_diff(block as *const _ as usize)
                             < segment.page_size(page.as_ptr())
                     },
-                    "block and next not in the same block: {block:p}, next {:p}",
-                    block.next
+                    "block and next not in the same block: {block:p}, next {next:p}",
+                );
+                #[cfg(all(feature = "fill_on_free", debug_assertions))]
+                check_free_fill(
+                    block as *const _ as *const u8,
+                    unsafe { page.as_ref() }.block_size,
                 );
+                // a block in use should not look like a free block
+                #[cfg(feature = "double_free")]
+                {
+                    block.next = null_mut();
+                }
                 let page = unsafe { page.as_mut() };
-                page.free = block.next;
+                page.free = next;
                 page.used += 1;
                 // convert to usize first to avoid UB
                 // > Undefined Behavior: attempting a write access using ... at ...,
@@ -111,6 +180,10 @@ impl Page {
         let page_mut = unsafe { page.as_mut() };
         if unsafe { page_mut.flags.flag_16 } == 0 {
             // fast path
+            #[cfg(feature = "double_free")]
+            if page_mut.is_double_free(p.cast()) {
+                return;
+            }
             page_mut.free_block_core(p.cast());
             if page_mut.all_free() && page_mut.should_retire() {
                 heap.retire_page(page, false, os_alloc);
@@ -124,6 +197,25 @@ impl Page {
             } else {
                 p.cast()
             };
+            // guarded pages are always marked as aligned
+            #[cfg(feature = "guarded")]
+            if unsafe { segment.as_ref() }.guarded().is_some() {
+                if page_mut.all_free() {
+                    // the block is inaccessible in the quarantine
+                    report(Error::DoubleFree {
+                        block: block as usize,
+                    });
+                } else {
+                    page_mut.free_block_core(block);
+                    let full = unsafe { page_mut.flags.flags }.full;
+                    heap.quarantine_guarded(page, full, os_alloc);
+                }
+                return;
+            }
+            #[cfg(feature = "double_free")]
+            if page_mut.is_double_free(block) {
+                return;
+            }
             page_mut.free_block_core(block);
             if page_mut.all_free() {
                 if page_mut.should_retire() {
@@ -131,42 +223,216 @@ impl Page {
                     heap.retire_page(page, full, os_alloc);
                 }
             } else if unsafe { page_mut.flags.flags }.full {
-                page_mut.set_full(false);
-                heap.page_queue_push_back(page);
+                heap.page_unfull(page);
+            }
+        }
+    }
+
+    // _mi_free_block_mt
+    /// Free a block in a page owned by another heap, or without holding the owning heap.
+    ///
+    /// If the page is full, the block is pushed to the list returned by `delayed_free`,
+    /// which should be drained by the owner later.
+    #[cfg(atomic_free)]
+    pub fn free_block_mt<'a>(
+        segment: &'a Segment,
+        p: *mut u8,
+        delayed_free: impl FnOnce(&'a Segment) -> &'a AtomicPtr<Block>,
+    ) {
+        let page = segment.page_of_ptr(p);
+        // `has_aligned` may be changed by the owner, so always find the start of the block
+        let page = unsafe { &*page.as_ptr() };
+        let offset = p as usize - segment.page_payload_addr(page);
+        let block = (p as usize - offset % page.block_size) as *mut Block;
+        #[cfg(any(feature = "zero_on_free", feature = "fill_on_free"))]
+        fill_free(block as *mut u8, page.block_size);
+
+        let mut tfree = page.thread_free.load(Ordering::Relaxed);
+        loop {
+            let use_delayed = tfree & DELAYED_MASK == USE_DELAYED_FREE;
+            let new = if use_delayed {
+                (tfree & !DELAYED_MASK) | DELAYED_FREEING
+            } else {
+                unsafe { (*block).next = page.encode((tfree & !DELAYED_MASK) as _) };
+                block as usize | (tfree & DELAYED_MASK)
+            };
+            match page.thread_free.compare_exchange_weak(
+                tfree,
+                new,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) if use_delayed => break,
+                Ok(_) => return,
+                Err(current) => tfree = current,
             }
         }
+
+        // the page is in the full page queue and never searched, notify the owner
+        push_atomic(delayed_free(segment), page, block);
+
+        // only one thread can be in `DELAYED_FREEING`, so clearing the tag is enough
+        page.thread_free.fetch_and(!DELAYED_MASK, Ordering::Release);
     }
 
     fn free_block_core(&mut self, block: *mut Block) {
         debug_assert!(self.used > 0);
-        unsafe { (*block).next = self.local_free };
+        #[cfg(any(feature = "zero_on_free", feature = "fill_on_free"))]
+        fill_free(block as *mut u8, self.block_size);
+        unsafe { (*block).next = self.encode(self.local_free) };
         self.local_free = block;
         self.used -= 1;
     }
 
-    pub fn init(&mut self, page_size: usize, block_size: usize) {
+    pub fn init(
+        &mut self,
+        page_size: usize,
+        block_size: usize,
+        #[cfg(feature = "secure")] random: &mut Random,
+    ) {
         debug_assert_eq!(self.reserved, 0, "block double inited");
+        #[cfg(feature = "secure")]
+        {
+            self.keys = [random.next(), random.next()];
+        }
         self.block_size = block_size;
         self.bin = bin_for_size(block_size) as u8;
         self.reserved = (page_size / block_size) as _;
-        self.extend();
+        self.extend(
+            #[cfg(feature = "secure")]
+            random,
+        );
     }
 
     pub fn free_collect(&mut self) {
+        #[cfg(atomic_free)]
+        self.thread_free_collect();
+
         if !self.local_free.is_null() {
             match unsafe { self.free.as_mut() } {
                 None => self.free = self.local_free,
                 Some(mut tail) => {
-                    while let Some(next) = unsafe { tail.next.as_mut() } {
+                    while let Some(next) = unsafe { self.next_block(tail).as_mut() } {
                         tail = next;
                     }
-                    tail.next = self.local_free;
+                    tail.next = self.encode(self.local_free);
                 }
             }
             self.local_free = null_mut();
+            // freed blocks are cleared except for the link with `zero_on_free`
+            if !cfg!(feature = "zero_on_free") {
+                self.free_is_zero = false;
+            }
         }
     }
 
+    // _mi_page_thread_free_collect
+    #[cfg(atomic_free)]
+    fn thread_free_collect(&mut self) {
+        let mut tfree = self.thread_free.load(Ordering::Relaxed);
+        loop {
+            if tfree & !DELAYED_MASK == 0 {
+                return;
+            }
+            match self.thread_free.compare_exchange_weak(
+                tfree,
+                tfree & DELAYED_MASK,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => tfree = current,
+            }
+        }
+
+        let head = (tfree & !DELAYED_MASK) as *mut Block;
+        let mut tail = unsafe { &mut *head };
+        let mut count = 1;
+        while let Some(next) = unsafe { self.next_block(tail).as_mut() } {
+            // the list cannot be longer than `used` unless a block is pushed twice
+            #[cfg(feature = "double_free")]
+            if count == self.used {
+                (tail, count) = self.cut_thread_free_cycle(head);
+                break;
+            }
+            tail = next;
+            count += 1;
+        }
+        tail.next = self.encode(self.local_free);
+        self.local_free = head;
+        debug_assert!(self.used >= count);
+        self.used -= count;
+    }
+
+    /// Cut the list of blocks freed by other threads at the first repeated block.
+    ///
+    /// Pushing a block again makes it point to the newer blocks, so the list loops back to
+    /// itself, and the blocks after the first push are lost. Returns the tail and the length.
+    #[cfg(all(feature = "double_free", atomic_free))]
+    fn cut_thread_free_cycle<'a>(&self, head: *mut Block) -> (&'a mut Block, u16) {
+        let mut tail = unsafe { &mut *head };
+        let mut count = 1;
+        while let Some(next) = unsafe { self.next_block(tail).as_mut() } {
+            if self
+                .list_iter(head)
+                .take(count as usize)
+                .any(|block| block == next)
+            {
+                report(Error::DoubleFree {
+                    block: next as *mut _ as usize,
+                });
+                tail.next = self.encode(null_mut());
+                break;
+            }
+            tail = next;
+            count += 1;
+        }
+        (tail, count)
+    }
+
+    // _mi_page_use_delayed_free
+    #[cfg(atomic_free)]
+    fn use_delayed_free(&mut self, delay: usize) {
+        let mut tfree = self.thread_free.load(Ordering::Relaxed);
+        loop {
+            if tfree & DELAYED_MASK == DELAYED_FREEING {
+                // wait for the other thread to finish pushing to the heap
+                core::hint::spin_loop();
+                tfree = self.thread_free.load(Ordering::Relaxed);
+                continue;
+            }
+            if tfree & DELAYED_MASK == delay {
+                return;
+            }
+            match self.thread_free.compare_exchange_weak(
+                tfree,
+                (tfree & !DELAYED_MASK) | delay,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => tfree = current,
+            }
+        }
+    }
+
+    /// Clear a `DELAYED_FREEING` tag left in the child of `fork` by a thread that no longer
+    /// exists there, so that the owner does not wait for it forever. Its block may leak.
+    #[cfg(all(atomic_free, feature = "mmap"))]
+    pub fn reset_delayed_freeing(&self) {
+        let tfree = self.thread_free.load(Ordering::Relaxed);
+        if tfree & DELAYED_MASK == DELAYED_FREEING {
+            self.thread_free
+                .store(tfree & !DELAYED_MASK, Ordering::Relaxed);
+        }
+    }
+
+    /// Stop other threads from pushing to the owning heap's `thread_delayed_free`.
+    #[cfg(feature = "thread_local")]
+    pub fn abandon(&mut self) {
+        self.use_delayed_free(NEVER_DELAYED_FREE);
+    }
+
     // _mi_page_retire
     fn should_retire(&mut self) -> bool {
         fn mostly_used(p: *mut Page) -> bool {
@@ -185,31 +451,294 @@ impl Page {
         }
     }
 
-    pub fn extend(&mut self) {
+    // mi_page_extend_free
+    /// Add more blocks to the free list. With the `secure` feature, the blocks are shuffled.
+    pub fn extend(&mut self, #[cfg(feature = "secure")] random: &mut Random) {
         if self.immediate_available() || self.capacity >= self.reserved {
             return;
         }
+        // the free list is empty, and new blocks are untouched
+        self.free_is_zero = self.is_zero && !cfg!(all(feature = "fill_on_free", debug_assertions));
         let bsize = self.block_size;
         let max_extend = (MI_MAX_EXTEND_SIZE / bsize).max(MI_MIN_EXTEND);
         let extend = ((self.reserved - self.capacity) as usize).min(max_extend);
         let segment = unsafe { Segment::of_ptr(self).as_mut().unwrap_unchecked() };
         let payload_start = segment.page_payload_addr(self);
-        let mut addr = payload_start + bsize * self.capacity as usize;
+        let start = payload_start + bsize * self.capacity as usize;
+        // new blocks are checked for the fill when allocated
+        #[cfg(all(feature = "fill_on_free", debug_assertions))]
+        unsafe {
+            (start as *mut u8).write_bytes(FREE_FILL, bsize * extend)
+        };
+        #[cfg(feature = "secure")]
+        if extend >= MI_MIN_SLICES {
+            self.extend_shuffled(start, extend, random);
+            self.capacity += extend as u16;
+            return;
+        }
+        let mut addr = start;
         let end = addr + bsize * (extend - 1);
         self.free = addr as _;
         while addr != end {
             let next = addr + bsize;
-            unsafe { (*(addr as *mut Block)).next = next as _ };
+            unsafe { (*(addr as *mut Block)).next = self.encode(next as _) };
             addr = next;
         }
-        unsafe { (*(addr as *mut Block)).next = null_mut() };
+        unsafe { (*(addr as *mut Block)).next = self.encode(null_mut()) };
         self.capacity += extend as u16;
     }
 
+    // mi_page_free_list_extend_secure
+    /// Link `extend` blocks from `start` into the free list in a random order.
+    ///
+    /// The blocks are split into slices, and the list walks through the slices randomly,
+    /// taking the next block of each visited slice.
+    #[cfg(feature = "secure")]
+    fn extend_shuffled(&mut self, start: usize, extend: usize, random: &mut Random) {
+        let bsize = self.block_size;
+        let mut shift = MI_MAX_SLICE_SHIFT;
+        while extend >> shift == 0 {
+            shift -= 1;
+        }
+        let slice_count = 1 << shift;
+        let slice_extend = extend / slice_count;
+
+        // the next block and the number of remaining blocks of each slice
+        let mut blocks = [0; 1 << MI_MAX_SLICE_SHIFT];
+        let mut counts = [0; 1 << MI_MAX_SLICE_SHIFT];
+        for i in 0..slice_count {
+            blocks[i] = start + i * slice_extend * bsize;
+            counts[i] = slice_extend;
+        }
+        // the last slice holds the remainder
+        counts[slice_count - 1] += extend % slice_count;
+
+        let mut rnd = random.next();
+        let mut current = rnd % slice_count;
+        counts[current] -= 1;
+        self.free = blocks[current] as _;
+        for i in 1..extend {
+            // use each byte of a random number to choose a slice
+            let round = i % MI_INTPTR_SIZE;
+            if round == 0 {
+                rnd = random.next();
+            }
+            let mut next = (rnd >> (8 * round)) & (slice_count - 1);
+            while counts[next] == 0 {
+                next = (next + 1) % slice_count;
+            }
+            counts[next] -= 1;
+            let block = blocks[current];
+            blocks[current] += bsize;
+            // read after advancing, in case `next` is `current`
+            unsafe { (*(block as *mut Block)).next = self.encode(blocks[next] as _) };
+            current = next;
+        }
+        unsafe { (*(blocks[current] as *mut Block)).next = self.encode(null_mut()) };
+    }
+
     pub const fn free(&self) -> *mut Block {
         self.free
     }
 
+    // mi_padding_init
+    /// Write the canary after `size` bytes at `p`, at the end of its block.
+    #[cfg(feature = "padding")]
+    pub fn init_padding(p: *mut u8, size: usize) {
+        let segment = unsafe { &*Segment::of_ptr(p) };
+        let page = unsafe { segment.page_of_ptr(p).as_ref() };
+        let block = page.block_of(segment, p);
+        let padding = block as usize + page.block_size - MI_PADDING_SIZE;
+        let end = p as usize + size;
+        let delta = padding - end;
+        unsafe {
+            let padding = padding as *mut usize;
+            padding.write(page.canary(block, delta));
+            padding.wrapping_add(1).write(delta);
+            (end as *mut u8).write_bytes(MI_DEBUG_PADDING, delta.min(MI_MAX_ALIGN_SIZE));
+        }
+    }
+
+    // mi_check_padding
+    /// Check the canary written by [`init_padding`](Self::init_padding), and report
+    /// [`Error::BufferOverflow`] if it is overwritten.
+    #[cfg(feature = "padding")]
+    pub fn check_padding(segment: &Segment, p: *mut u8) {
+        // guarded allocations are not padded
+        #[cfg(feature = "guarded")]
+        if segment.guarded().is_some() {
+            return;
+        }
+        let page = unsafe { segment.page_of_ptr(p).as_ref() };
+        let block = page.block_of(segment, p);
+        let padding = block as usize + page.block_size - MI_PADDING_SIZE;
+        let (canary, delta) = unsafe {
+            let padding = padding as *const usize;
+            (padding.read(), padding.wrapping_add(1).read())
+        };
+        let valid = delta <= padding - p as usize && canary == page.canary(block, delta) && {
+            let end = padding - delta;
+            let len = delta.min(MI_MAX_ALIGN_SIZE);
+            let bytes = unsafe { core::slice::from_raw_parts(end as *const u8, len) };
+            bytes.iter().all(|&byte| byte == MI_DEBUG_PADDING)
+        };
+        if !valid {
+            report(Error::BufferOverflow {
+                ptr: p as usize,
+                size: padding - delta.min(padding - p as usize) - p as usize,
+            });
+        }
+    }
+
+    /// Number of bytes from `p` to the end of its block that can be used,
+    /// excluding the padding with the `padding` feature.
+    pub fn usable_size(segment: &Segment, p: *const u8) -> usize {
+        let page = unsafe { segment.page_of_ptr(p).as_ref() };
+        let block = page.block_of(segment, p);
+        let size = block as usize + page.block_size - p as usize;
+        // guarded allocations are not padded
+        #[cfg(feature = "guarded")]
+        if segment.guarded().is_some() {
+            return size;
+        }
+        #[cfg(feature = "padding")]
+        let size = size - MI_PADDING_SIZE;
+        size
+    }
+
+    // mi_page_usable_size_of
+    /// The size requested for the allocation at `p`, recorded by
+    /// [`init_padding`](Self::init_padding).
+    #[cfg(feature = "padding")]
+    pub fn requested_size(segment: &Segment, p: *const u8) -> usize {
+        #[cfg(feature = "guarded")]
+        if segment.guarded().is_some() {
+            return Self::usable_size(segment, p);
+        }
+        let page = unsafe { segment.page_of_ptr(p).as_ref() };
+        let block = page.block_of(segment, p);
+        let padding = block as usize + page.block_size - MI_PADDING_SIZE;
+        let delta = unsafe { (padding as *const usize).wrapping_add(1).read() };
+        // the delta may be overwritten by an overflow
+        (padding - p as usize).saturating_sub(delta)
+    }
+
+    /// The start of the block containing `p`.
+    fn block_of(&self, segment: &Segment, p: *const u8) -> *mut Block {
+        let offset = p as usize - segment.page_payload_addr(self);
+        (p as usize - offset % self.block_size) as _
+    }
+
+    // mi_ptr_encode_canary
+    #[cfg(feature = "padding")]
+    fn canary(&self, block: *mut Block, delta: usize) -> usize {
+        self.encode(block) as usize ^ delta
+    }
+
+    // mi_ptr_encode
+    /// Encode a pointer to be stored in a block of this page.
+    fn encode(&self, p: *mut Block) -> *mut Block {
+        #[cfg(feature = "secure")]
+        {
+            let [k0, k1] = self.keys;
+            ((p as usize ^ k1).rotate_left(k0 as u32).wrapping_add(k0)) as _
+        }
+        #[cfg(not(feature = "secure"))]
+        p
+    }
+
+    // mi_ptr_decode
+    /// Decode a pointer stored in a block of this page.
+    fn decode(&self, p: *mut Block) -> *mut Block {
+        #[cfg(feature = "secure")]
+        {
+            let [k0, k1] = self.keys;
+            ((p as usize).wrapping_sub(k0).rotate_right(k0 as u32) ^ k1) as _
+        }
+        #[cfg(not(feature = "secure"))]
+        p
+    }
+
+    // mi_block_next
+    /// Get the next block of `block` in this page.
+    ///
+    /// With the `secure` feature, a next block outside this page is reported as
+    /// [`Error::CorruptedFreeList`], and the list is truncated.
+    fn next_block(&self, block: &Block) -> *mut Block {
+        let next = self.decode(block.next);
+        #[cfg(feature = "secure")]
+        if !self.contains_block(next) {
+            report(Error::CorruptedFreeList {
+                block: block as *const _ as usize,
+            });
+            return null_mut();
+        }
+        next
+    }
+
+    // mi_is_in_same_page
+    /// Whether `block` is null or the start of a block in this page.
+    #[cfg(feature = "secure")]
+    fn contains_block(&self, block: *mut Block) -> bool {
+        if block.is_null() {
+            return true;
+        }
+        let segment = unsafe { &*Segment::of_ptr(self) };
+        let offset = (block as usize).wrapping_sub(segment.page_payload_addr(self));
+        offset < self.capacity as usize * self.block_size && offset.is_multiple_of(self.block_size)
+    }
+
+    // mi_check_is_double_free
+    /// Whether `block` is already in a free list of this page, which is reported as
+    /// [`Error::DoubleFree`].
+    ///
+    /// In release builds, the lists are only scanned if `block` looks like a free block, i.e.
+    /// it holds an encoded pointer to a block in this page. Debug builds also scan the first
+    /// [`MI_DEBUG_DOUBLE_FREE_SCAN`] entries of each list for other blocks.
+    #[cfg(feature = "double_free")]
+    fn is_double_free(&self, block: *mut Block) -> bool {
+        let looks_free = self.contains_block(self.decode(unsafe { (*block).next }));
+        if !cfg!(debug_assertions) && !looks_free {
+            return false;
+        }
+        let limit = if looks_free {
+            usize::MAX
+        } else {
+            MI_DEBUG_DOUBLE_FREE_SCAN
+        };
+        #[cfg(atomic_free)]
+        let thread_free = (self.thread_free.load(Ordering::Acquire) & !DELAYED_MASK) as *mut Block;
+        #[cfg(not(atomic_free))]
+        let thread_free = null_mut();
+        let double_free = [self.free, self.local_free, thread_free]
+            .into_iter()
+            .any(|list| self.list_contains(list, block, limit));
+        if double_free {
+            report(Error::DoubleFree {
+                block: block as usize,
+            });
+        }
+        double_free
+    }
+
+    // mi_list_contains
+    #[cfg(feature = "double_free")]
+    fn list_contains(&self, list: *mut Block, block: *mut Block, limit: usize) -> bool {
+        self.list_iter(list)
+            .take(limit)
+            .any(|current| current == block)
+    }
+
+    /// Iterate over a list of blocks in this page.
+    #[cfg(feature = "double_free")]
+    fn list_iter(&self, mut list: *mut Block) -> impl Iterator<Item = *mut Block> + '_ {
+        core::iter::from_fn(move || {
+            let current = list;
+            list = self.next_block(unsafe { current.as_ref() }?);
+            Some(current)
+        })
+    }
+
     pub fn immediate_available(&self) -> bool {
         !self.free.is_null()
     }
@@ -224,6 +753,12 @@ impl Page {
 
     pub fn set_full(&mut self, full: bool) {
         self.flags.flags.full = full;
+        #[cfg(atomic_free)]
+        self.use_delayed_free(if full {
+            USE_DELAYED_FREE
+        } else {
+            NO_DELAYED_FREE
+        });
     }
 
     pub const fn all_free(&self) -> bool {
@@ -241,6 +776,26 @@ impl Page {
     pub fn set_in_use(&mut self, in_use: bool) {
         self.in_use = in_use
     }
+
+    pub fn set_zero(&mut self, is_zero: bool) {
+        self.is_zero = is_zero;
+    }
+
+    // the zeroing part of _mi_page_malloc_zero
+    /// Zero the first `size` bytes of `block`, which is just allocated from this page.
+    /// Only the link needs to be cleared if the free blocks are already zero.
+    pub fn zero_block(&self, block: NonNull<u8>, size: usize) {
+        if self.free_is_zero {
+            unsafe {
+                block
+                    .cast::<Block>()
+                    .as_ptr()
+                    .write(Block { next: null_mut() })
+            };
+        } else {
+            unsafe { block.write_bytes(0, size) };
+        }
+    }
 }
 
 mod empty_page {
@@ -253,14 +808,20 @@ mod empty_page {
 
     static EMPTY_PAGE: EmptyPage = EmptyPage(Page {
         in_use: false,
+        is_zero: false,
+        free_is_zero: false,
         flags: PageFlagUnion { flag_16: 0 },
         capacity: 0,
         reserved: 0,
         free: null_mut(),
         used: 0,
         local_free: null_mut(),
+        #[cfg(atomic_free)]
+        thread_free: AtomicUsize::new(0),
         block_size: 0,
         bin: 0,
+        #[cfg(feature = "secure")]
+        keys: [0; 2],
         next: null_mut(),
         prev: null_mut(),
     });
@@ -271,3 +832,38 @@ mod empty_page {
 }
 
 pub use empty_page::empty_page;
+
+/// Push a block to an atomic list of blocks.
+#[cfg(atomic_free)]
+pub fn push_atomic(list: &AtomicPtr<Block>, page: &Page, block: *mut Block) {
+    let mut head = list.load(Ordering::Relaxed);
+    loop {
+        unsafe { (*block).next = page.encode(head) };
+        match list.compare_exchange_weak(head, block, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(current) => head = current,
+        }
+    }
+}
+
+/// Take all blocks from an atomic list of blocks.
+#[cfg(atomic_free)]
+pub fn take_atomic(list: &AtomicPtr<Block>) -> impl Iterator<Item = *mut Block> {
+    // avoid writing to the shared list when it is empty
+    let mut block = if list.load(Ordering::Relaxed).is_null() {
+        null_mut()
+    } else {
+        list.swap(null_mut(), Ordering::Acquire)
+    };
+    core::iter::from_fn(move || {
+        let current = NonNull::new(block)?;
+        let next = unsafe { current.as_ref() }.next;
+        #[cfg(feature = "secure")]
+        let next = {
+            let segment = unsafe { &*Segment::of_ptr(current.as_ptr()) };
+            unsafe { segment.page_of_ptr(current.as_ptr().cast()).as_ref() }.decode(next)
+        };
+        block = next;
+        Some(current.as_ptr())
+    })
+}
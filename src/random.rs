@@ -0,0 +1,53 @@
+/// A small non-cryptographic random number generator (SplitMix64).
+///
+/// Unless a seed is given, it is seeded lazily from addresses, which are randomized by ASLR,
+/// and from the OS random source with the `std` feature.
+#[derive(Clone, Copy)]
+pub struct Random {
+    state: u64,
+    seeded: bool,
+}
+
+impl Random {
+    pub const fn new() -> Self {
+        Self {
+            state: 0,
+            seeded: false,
+        }
+    }
+
+    pub const fn with_seed(seed: u64) -> Self {
+        Self {
+            state: seed,
+            seeded: true,
+        }
+    }
+
+    // _mi_random_next
+    pub fn next(&mut self) -> usize {
+        if !self.seeded {
+            self.state = self.seed();
+            self.seeded = true;
+        }
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as usize
+    }
+
+    // _mi_os_random_weak
+    fn seed(&self) -> u64 {
+        let local = 0u8;
+        #[allow(unused_mut)]
+        let mut seed = self as *const _ as u64
+            ^ (&raw const local as u64).rotate_left(21)
+            ^ (Self::seed as fn(&Self) -> u64 as usize as u64).rotate_left(42);
+        #[cfg(feature = "std")]
+        {
+            use std::hash::{BuildHasher, RandomState};
+            seed ^= RandomState::new().hash_one(seed);
+        }
+        seed
+    }
+}
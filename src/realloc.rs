@@ -0,0 +1,61 @@
+//! Reallocation shared by [`Mimalloc`](crate::Mimalloc) and the [`GlobalAlloc`](core::alloc::GlobalAlloc) wrappers.
+
+#[cfg(all(
+    feature = "std",
+    any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api")
+))]
+use crate::emergency;
+use crate::heap::Heap;
+use core::alloc::Layout;
+
+/// Resize the block at `ptr` to `new_size` bytes without moving it.
+/// Returns whether it is resized.
+pub fn try_in_place(ptr: *mut u8, layout: Layout, new_size: usize, zero: bool) -> bool {
+    #[cfg(all(
+        feature = "std",
+        any(feature = "std_mutex", feature = "spin_mutex", feature = "lock_api")
+    ))]
+    if emergency::contains(ptr) {
+        return false;
+    }
+    Heap::expand(ptr, layout.size(), new_size, zero)
+}
+
+/// Copy the contents of the old block to the new block, and zero the rest with `zero`.
+///
+/// # Safety
+///
+/// `ptr` must be valid for `size` bytes, and `new_ptr` must be valid for `new_size` bytes.
+pub unsafe fn copy(ptr: *mut u8, size: usize, new_ptr: *mut u8, new_size: usize, zero: bool) {
+    new_ptr.copy_from_nonoverlapping(ptr, size.min(new_size));
+    if zero && new_size > size {
+        ((new_ptr as usize + size) as *mut u8).write_bytes(0, new_size - size);
+    }
+}
+
+// _mi_heap_realloc_zero
+/// [`GlobalAlloc::realloc`](core::alloc::GlobalAlloc::realloc) that resizes the block in place if possible,
+/// and moves it with the allocation functions of `allocator` otherwise.
+///
+/// # Safety
+///
+/// See [`GlobalAlloc::realloc`](core::alloc::GlobalAlloc::realloc).
+#[cfg(atomic_free)]
+pub unsafe fn realloc<G: core::alloc::GlobalAlloc + ?Sized>(
+    allocator: &G,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+    zero: bool,
+) -> *mut u8 {
+    if try_in_place(ptr, layout, new_size, zero) {
+        return ptr;
+    }
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        copy(ptr, layout.size(), new_ptr, new_size, zero);
+        allocator.dealloc(ptr, layout);
+    }
+    new_ptr
+}
@@ -1,12 +1,26 @@
 // NOTE: Avoid using `ptr::{add, offset_from}` when unsafe (UB). Convert to usize instead.
 
 use crate::constants::*;
+#[cfg(feature = "segment_map")]
+use crate::error::{report, Error};
+#[cfg(feature = "guard_pages")]
+use crate::guard::GuardPages;
+#[cfg(feature = "guarded")]
+use crate::guarded::{self, GuardedBlock};
 use crate::heap::Heap;
 use crate::list::impl_list_item;
 use crate::page::Page;
+#[cfg(feature = "segment_map")]
+use crate::segment_map;
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem::size_of;
 use core::ptr::{null_mut, NonNull};
+#[cfg(feature = "thread_local")]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Segments whose owning threads have exited, linked by `next`.
+#[cfg(feature = "thread_local")]
+static ABANDONED: AtomicPtr<Segment> = AtomicPtr::new(null_mut());
 
 pub struct Segment {
     next: *mut Self,
@@ -16,6 +30,18 @@ pub struct Segment {
     segment_size: usize,
     info_size: usize,
     page_size: usize,
+    /// Size of the inaccessible guard at the end of each page.
+    #[cfg(feature = "guard_pages")]
+    guard_size: usize,
+    /// Index of the shard owning this segment.
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    shard: usize,
+    /// The heap owning this segment.
+    #[cfg(feature = "thread_local")]
+    heap: AtomicPtr<Heap>,
+    /// The allocation if this is a guarded segment.
+    #[cfg(feature = "guarded")]
+    guarded: Option<GuardedBlock>,
     // pages with a variable length at the end
 }
 
@@ -24,21 +50,45 @@ pub enum PageKind {
     Small,
     Large,
     Huge(usize),
+    /// A page holding a single allocation of `size` bytes aligned to `align`, with a guard page
+    /// on each side.
+    #[cfg(feature = "guarded")]
+    Guarded {
+        size: usize,
+        align: usize,
+    },
 }
 
 impl_list_item!(Segment);
 
 impl Segment {
     /// Allocate a segment and a page in it.
+    ///
+    /// With `guard_pages`, the last OS page of each page is protected.
+    /// A guarded page is also preceded by a protected OS page, and fails without guard pages.
+    ///
+    /// `is_zero` tells that the OS allocator returns zeroed memory.
     pub fn alloc<A: GlobalAlloc>(
         page_kind: PageKind,
         os_alloc: &A,
+        is_zero: bool,
+        #[cfg(feature = "guard_pages")] guard_pages: Option<GuardPages>,
     ) -> Option<(NonNull<Self>, NonNull<Page>)> {
         const INFO_ALIGN: usize = if MI_MAX_ALIGN_SIZE < 16 {
             16
         } else {
             MI_MAX_ALIGN_SIZE
         };
+        #[cfg(feature = "guard_pages")]
+        let guard_size = guard_pages
+            .map(|guard_pages| unsafe { guard_pages.page_size(os_alloc) })
+            // a large OS page leaves too little room in small pages
+            .filter(|&size| size <= MI_SMALL_PAGE_SIZE / 4)
+            .unwrap_or(0);
+        #[cfg(not(feature = "guard_pages"))]
+        let guard_size = 0;
+        #[cfg(feature = "guarded")]
+        let mut guarded = None;
         let (capacity, segment_size, info_size, page_size) = match page_kind {
             PageKind::Small => {
                 const {
@@ -65,19 +115,56 @@ impl Segment {
             PageKind::Huge(size) => {
                 const INFO_SIZE: usize =
                     (size_of::<Segment>() + size_of::<Page>()).next_multiple_of(INFO_ALIGN);
-                let segment_size = (size + INFO_SIZE).next_multiple_of(MI_PAGE_HUGE_ALIGN);
+                let segment_size =
+                    (size + INFO_SIZE + guard_size).next_multiple_of(MI_PAGE_HUGE_ALIGN);
                 (1, segment_size, INFO_SIZE, segment_size)
             }
+            #[cfg(feature = "guarded")]
+            PageKind::Guarded { size, align } => {
+                let os_page_size = unsafe { guard_pages?.page_size(os_alloc) };
+                if size_of::<Self>() + size_of::<Page>() > os_page_size {
+                    return None;
+                }
+                // the info, the leading guard, the slot and the trailing guard
+                let slot_size = size
+                    .checked_add(align - 1)?
+                    .checked_next_multiple_of(os_page_size)?;
+                let segment_size = slot_size.checked_add(3 * os_page_size)?;
+                guarded = Some((os_page_size, size, align));
+                (1, segment_size, 2 * os_page_size, segment_size)
+            }
         };
+        #[cfg(feature = "guarded")]
+        let guard_size = guarded.map_or(guard_size, |(os_page_size, ..)| os_page_size);
 
         let layout = unsafe { Layout::from_size_align_unchecked(segment_size, MI_SEGMENT_SIZE) };
         let p = unsafe { os_alloc.alloc(layout) as *mut Self };
 
         let segment = NonNull::new(p)?;
+
+        // _mi_segment_protect
+        #[cfg(feature = "guard_pages")]
+        if let Some(guard_pages) = guard_pages.filter(|_| guard_size != 0) {
+            for i in 1..=capacity {
+                let guard = (p as usize + i * page_size - guard_size) as *mut u8;
+                unsafe { guard_pages.protect(os_alloc, guard, guard_size) };
+            }
+            #[cfg(feature = "guarded")]
+            if guarded.is_some() {
+                let guard = (p as usize + guard_size) as *mut u8;
+                unsafe { guard_pages.protect(os_alloc, guard, guard_size) };
+            }
+        }
         let pages_base = Self::pages_base_addr(segment.as_ptr()) as *mut Page;
 
         // clear pages
         unsafe { pages_base.write_bytes(0, capacity) };
+        if is_zero {
+            for i in 0..capacity {
+                let page = (pages_base as usize + i * size_of::<Page>()) as *mut Page;
+                unsafe { (*page).set_zero(true) };
+            }
+        }
 
         let value = Self {
             next: null_mut(),
@@ -87,8 +174,28 @@ impl Segment {
             segment_size,
             info_size,
             page_size,
+            #[cfg(feature = "guard_pages")]
+            guard_size,
+            #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+            shard: 0,
+            #[cfg(feature = "thread_local")]
+            heap: AtomicPtr::new(null_mut()),
+            #[cfg(feature = "guarded")]
+            guarded: guarded.map(|(_, size, align)| GuardedBlock {
+                // right before the trailing guard, so that an overflow faults immediately
+                ptr: (p as usize + segment_size - guard_size - size) & !(align - 1),
+                size,
+            }),
         };
         unsafe { segment.write(value) };
+        #[cfg(feature = "guarded")]
+        if guarded.is_some() && !guarded::insert(segment.as_ptr()) {
+            // too many guarded allocations
+            unsafe { os_alloc.dealloc(p.cast(), layout) };
+            return None;
+        }
+        #[cfg(feature = "segment_map")]
+        segment_map::insert(segment.as_ptr());
 
         let mut page = unsafe { NonNull::new_unchecked(pages_base) };
         unsafe { page.as_mut() }.set_in_use(true);
@@ -96,6 +203,17 @@ impl Segment {
         Some((segment, page))
     }
 
+    #[cfg(feature = "guarded")]
+    pub const fn guarded(&self) -> Option<GuardedBlock> {
+        self.guarded
+    }
+
+    /// Whether `addr` is in this segment.
+    #[cfg(all(feature = "guarded", feature = "mmap"))]
+    pub fn contains(&self, addr: usize) -> bool {
+        addr.wrapping_sub(self as *const _ as usize) < self.segment_size
+    }
+
     pub fn find_free_small_page(&self) -> NonNull<Page> {
         debug_assert_eq!(self.capacity, MI_SMALL_PAGES_PER_SEGMENT);
         let mut addr = Self::pages_base_addr(self);
@@ -115,10 +233,81 @@ impl Segment {
         self.used == self.capacity
     }
 
+    #[cfg(feature = "thread_local")]
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
     pub fn increment_used(&mut self) {
         self.used += 1;
     }
 
+    #[cfg(feature = "thread_local")]
+    pub fn heap(&self) -> *mut Heap {
+        self.heap.load(Ordering::Acquire)
+    }
+
+    #[cfg(feature = "thread_local")]
+    pub fn set_heap(&self, heap: *mut Heap) {
+        self.heap.store(heap, Ordering::Release);
+    }
+
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    pub fn set_shard(&mut self, shard: usize) {
+        self.shard = shard;
+    }
+
+    /// Push a segment to the global list of abandoned segments.
+    /// It must not be in any other list.
+    #[cfg(feature = "thread_local")]
+    pub fn abandon(mut segment: NonNull<Self>) {
+        let seg = unsafe { segment.as_mut() };
+        seg.set_heap(null_mut());
+        let mut head = ABANDONED.load(Ordering::Relaxed);
+        loop {
+            seg.next = head;
+            match ABANDONED.compare_exchange_weak(
+                head,
+                segment.as_ptr(),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Take all segments from the global list of abandoned segments.
+    #[cfg(feature = "thread_local")]
+    pub fn take_abandoned() -> impl Iterator<Item = NonNull<Self>> {
+        let mut segment = ABANDONED.swap(null_mut(), Ordering::Acquire);
+        core::iter::from_fn(move || {
+            let mut current = NonNull::new(segment)?;
+            let current_mut = unsafe { current.as_mut() };
+            segment = current_mut.next;
+            // clear the links so that it is not considered to be in another list
+            current_mut.next = null_mut();
+            current_mut.prev = null_mut();
+            Some(current)
+        })
+    }
+
+    /// Iterate over pages in use.
+    #[cfg(feature = "thread_local")]
+    pub fn pages(&self) -> impl Iterator<Item = NonNull<Page>> {
+        let base = Self::pages_base_addr(self);
+        (0..self.capacity).filter_map(move |i| {
+            let page = (base + i * size_of::<Page>()) as *mut Page;
+            unsafe { (*page).in_use() }.then(|| unsafe { NonNull::new_unchecked(page) })
+        })
+    }
+
     pub fn page_payload_addr(&self, page: *const Page) -> usize {
         let index = (page as usize - Self::pages_base_addr(self)) / size_of::<Page>();
         let base = self as *const _ as usize;
@@ -134,6 +323,31 @@ impl Segment {
         (ptr as usize & !MI_SEGMENT_MASK) as _
     }
 
+    // _mi_ptr_segment
+    /// Get the segment of a pointer to be freed, or `None` if it is null.
+    ///
+    /// With the `segment_map` feature, a pointer not in a live segment is reported as
+    /// [`Error::InvalidFree`] and also results in `None`.
+    pub fn of_freed_ptr<'a>(p: *mut u8) -> Option<&'a Self> {
+        #[cfg(feature = "segment_map")]
+        if segment_map::is_foreign(p) {
+            report(Error::InvalidFree { ptr: p as usize });
+            return None;
+        }
+        unsafe { Self::of_ptr(p).as_ref() }
+    }
+
+    /// Get the segment of an allocated pointer, or `None` if it is null.
+    ///
+    /// With the `segment_map` feature, a pointer not in a live segment also results in `None`.
+    pub fn of_allocated_ptr<'a>(p: *const u8) -> Option<&'a Self> {
+        #[cfg(feature = "segment_map")]
+        if segment_map::is_foreign(p) {
+            return None;
+        }
+        unsafe { Self::of_ptr(p).as_ref() }
+    }
+
     pub fn page_of_ptr(&self, ptr: *const u8) -> NonNull<Page> {
         let offset = ptr as usize - self as *const _ as usize;
         let index = offset / self.page_size;
@@ -146,13 +360,21 @@ impl Segment {
     }
 
     pub fn page_size(&self, page: *const Page) -> usize {
+        let page_size = self.page_size - self.guard_size();
         if (page as usize - self as *const _ as usize) < self.page_size {
-            self.page_size - self.info_size
+            page_size - self.info_size
         } else {
-            self.page_size
+            page_size
         }
     }
 
+    fn guard_size(&self) -> usize {
+        #[cfg(feature = "guard_pages")]
+        return self.guard_size;
+        #[cfg(not(feature = "guard_pages"))]
+        0
+    }
+
     pub fn remove_a_page<A: GlobalAlloc>(
         mut segment: NonNull<Self>,
         heap: &mut Heap,
@@ -163,6 +385,12 @@ impl Segment {
 
         if seg.used == 0 {
             heap.remove_small_free_segment(seg);
+            #[cfg(feature = "segment_map")]
+            segment_map::remove(seg);
+            #[cfg(feature = "guarded")]
+            if seg.guarded.is_some() {
+                guarded::remove(seg);
+            }
             unsafe {
                 let layout = Layout::from_size_align_unchecked(seg.segment_size, MI_SEGMENT_SIZE);
                 os_alloc.dealloc(segment.as_ptr().cast(), layout);
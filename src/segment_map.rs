@@ -0,0 +1,50 @@
+//! A bitmap over the address space recording the live segments, similar to `mi_segment_map`.
+
+use crate::constants::*;
+use crate::segment::Segment;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Segments at or above this address are not recorded.
+const MI_MAX_ADDRESS: u64 = if MI_INTPTR_SIZE == 8 {
+    1 << 48
+} else {
+    1 << 32
+};
+const MI_SEGMENT_MAP_BITS: usize = (MI_MAX_ADDRESS >> MI_SEGMENT_SHIFT) as usize;
+const MI_SEGMENT_MAP_WSIZE: usize = MI_SEGMENT_MAP_BITS / usize::BITS as usize;
+
+static MI_SEGMENT_MAP: [AtomicUsize; MI_SEGMENT_MAP_WSIZE] =
+    [const { AtomicUsize::new(0) }; MI_SEGMENT_MAP_WSIZE];
+
+// _mi_segment_map_index_of
+fn index_of<T>(p: *const T) -> Option<(usize, usize)> {
+    let index = p as usize >> MI_SEGMENT_SHIFT;
+    (index < MI_SEGMENT_MAP_BITS)
+        .then_some((index / usize::BITS as usize, index % usize::BITS as usize))
+}
+
+// _mi_segment_map_allocated_at
+pub fn insert(segment: *const Segment) {
+    if let Some((index, bit)) = index_of(segment) {
+        MI_SEGMENT_MAP[index].fetch_or(1 << bit, Ordering::Release);
+    }
+}
+
+// _mi_segment_map_freed_at
+pub fn remove(segment: *const Segment) {
+    if let Some((index, bit)) = index_of(segment) {
+        MI_SEGMENT_MAP[index].fetch_and(!(1 << bit), Ordering::Release);
+    }
+}
+
+/// Whether `p` is not null and not in the first [`MI_SEGMENT_SIZE`] bytes of a live segment.
+pub fn is_foreign(p: *const u8) -> bool {
+    !p.is_null() && !contains(p)
+}
+
+// mi_is_valid_pointer
+/// Whether `p` is in the first [`MI_SEGMENT_SIZE`] bytes of a live segment.
+pub fn contains(p: *const u8) -> bool {
+    index_of(p)
+        .is_some_and(|(index, bit)| MI_SEGMENT_MAP[index].load(Ordering::Acquire) & (1 << bit) != 0)
+}
@@ -0,0 +1,384 @@
+use crate::constants::*;
+use crate::emergency;
+#[cfg(all(feature = "fill_on_free", debug_assertions))]
+use crate::page::check_free_fill;
+#[cfg(any(feature = "zero_on_free", feature = "fill_on_free"))]
+use crate::page::fill_free;
+use crate::realloc;
+#[cfg(feature = "guarded")]
+use crate::segment::Segment;
+#[cfg(feature = "segment_map")]
+use crate::segment_map;
+use crate::utils::{bin_for_size, BLOCK_SIZE_FOR_BIN};
+use crate::{Mimalloc, MimallocMutexWrapper};
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::{Cell, UnsafeCell};
+use core::ptr::null_mut;
+#[cfg(feature = "guarded")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Maximum number of blocks in a magazine.
+const MAGAZINE_SIZE: usize = 64;
+/// Number of blocks to allocate or free at once under the lock.
+const BATCH_SIZE: usize = MAGAZINE_SIZE / 2;
+/// Number of bins of small blocks, which are the only ones cached.
+const CACHED_BINS: usize = bin_for_size(MI_SMALL_SIZE_MAX) + 1;
+
+/// A stack of free blocks of the same bin, linked by the first word of each block.
+#[derive(Clone, Copy)]
+struct Magazine {
+    head: *mut u8,
+    count: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, block: *mut u8) {
+        unsafe { block.cast::<*mut u8>().write(self.head) };
+        self.head = block;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+        let block = self.head;
+        self.head = unsafe { block.cast::<*mut u8>().read() };
+        self.count -= 1;
+        Some(block)
+    }
+}
+
+struct ThreadCache {
+    magazines: [Magazine; CACHED_BINS],
+    /// The wrapper that the cached blocks belong to.
+    owner: *const (),
+    /// Return all cached blocks to the owner.
+    flush_all: Option<unsafe fn(owner: *const (), cache: &mut ThreadCache)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Uninit,
+    Active,
+    /// The cache is being used, so it cannot be used by reentrant calls.
+    Busy,
+    /// The thread is exiting and its cache has been flushed.
+    Exited,
+}
+
+/// Flush the cache when the thread exits.
+struct ExitGuard;
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        STATE.set(ThreadState::Exited);
+        CACHE.with(|cache| {
+            let cache = unsafe { &mut *cache.get() };
+            if let Some(flush_all) = cache.flush_all {
+                unsafe { flush_all(cache.owner, cache) };
+            }
+        });
+    }
+}
+
+std::thread_local! {
+    // `CACHE` and `STATE` do not need drop, so they are still accessible when other
+    // thread-local destructors run
+    static CACHE: UnsafeCell<ThreadCache> = const {
+        UnsafeCell::new(ThreadCache {
+            magazines: [Magazine::new(); CACHED_BINS],
+            owner: core::ptr::null(),
+            flush_all: None,
+        })
+    };
+    static STATE: Cell<ThreadState> = const { Cell::new(ThreadState::Uninit) };
+    static EXIT_GUARD: ExitGuard = const { ExitGuard };
+}
+
+/// Put a per-thread cache of small free blocks in front of [`MimallocMutexWrapper`]
+/// and implement [`GlobalAlloc`].
+///
+/// Each thread keeps up to 64 free blocks for each small size class. The cache is refilled and
+/// flushed in batches, so the lock is only taken once for many small allocations.
+///
+/// The thread-local caches are shared by all instances, and only the first instance that uses
+/// the cache in a thread can use it in that thread. Cached blocks are returned when the thread
+/// exits, so the instance should live forever, typically as the [`global_allocator`].
+///
+/// With `zero_on_free` or `fill_on_free`, blocks are filled when they enter the cache, and with
+/// debug assertions, the fill is checked when they leave it. With `secure` (and so `double_free`
+/// and `padding`), and while guarded sampling is enabled, allocations bypass the cache.
+#[derive(Default)]
+pub struct MimallocCacheWrapper<A: GlobalAlloc> {
+    inner: MimallocMutexWrapper<A>,
+    /// Guarded sampling is enabled, so allocations bypass the cache to be sampled.
+    #[cfg(feature = "guarded")]
+    guarded: AtomicBool,
+}
+
+impl<A: GlobalAlloc> MimallocCacheWrapper<A> {
+    /// See [`Mimalloc::with_os_allocator`].
+    pub const fn with_os_allocator(os_alloc: A) -> Self {
+        Self::with_inner(MimallocMutexWrapper::with_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_zeroed_os_allocator`].
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self::with_inner(MimallocMutexWrapper::with_zeroed_os_allocator(os_alloc))
+    }
+
+    /// See [`Mimalloc::with_guard_pages`].
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self::with_inner(MimallocMutexWrapper::with_guard_pages(os_alloc))
+    }
+
+    const fn with_inner(inner: MimallocMutexWrapper<A>) -> Self {
+        Self {
+            inner,
+            #[cfg(feature = "guarded")]
+            guarded: AtomicBool::new(false),
+        }
+    }
+
+    #[cfg(feature = "deferred_free")]
+    /// See [`Mimalloc::register_deferred_free`].
+    pub fn register_deferred_free(&self, hook: crate::DeferredFreeHook<A>) {
+        self.inner.register_deferred_free(hook);
+    }
+
+    #[cfg(feature = "mmap")]
+    /// See [`MimallocMutexWrapper::register_fork_handlers`].
+    pub fn register_fork_handlers(&'static self) -> bool
+    where
+        A: 'static,
+    {
+        self.inner.register_fork_handlers()
+    }
+
+    #[cfg(feature = "segment_map")]
+    /// See [`Mimalloc::register_fallback`].
+    pub fn register_fallback(&self, fallback: &'static (dyn GlobalAlloc + Sync)) {
+        self.inner.register_fallback(fallback);
+    }
+
+    #[cfg(feature = "guarded")]
+    /// See [`Mimalloc::set_guarded_sample_rate`]. While it is enabled, allocations bypass
+    /// the cache, so that all of them can be sampled.
+    pub fn set_guarded_sample_rate(&self, rate: usize) {
+        self.inner.set_guarded_sample_rate(rate);
+        self.guarded.store(rate != 0, Ordering::Relaxed);
+    }
+
+    /// Return the blocks cached by the current thread and collect free memory.
+    /// See [`Mimalloc::collect`].
+    pub fn collect(&self) {
+        self.with_cache(|cache| unsafe { Self::flush_all(self.owner(), cache) });
+        self.inner.collect();
+    }
+
+    /// See [`Mimalloc::usable_size`]. It does not take the lock.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mimalloc::usable_size`].
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        self.inner.usable_size(ptr)
+    }
+
+    /// See [`Mimalloc::realloc_zeroed`].
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, true)
+    }
+
+    fn owner(&self) -> *const () {
+        self as *const Self as _
+    }
+
+    /// Run `f` with the cache of the current thread if it can be used by `self`.
+    fn with_cache<T>(&self, f: impl FnOnce(&mut ThreadCache) -> T) -> Option<T> {
+        match STATE.get() {
+            ThreadState::Active => {}
+            ThreadState::Busy | ThreadState::Exited => return None,
+            ThreadState::Uninit => {
+                STATE.set(ThreadState::Active);
+                // registering the destructor may allocate, so the cache must not be borrowed here
+                if EXIT_GUARD.try_with(|_| {}).is_err() {
+                    STATE.set(ThreadState::Exited);
+                    return None;
+                }
+            }
+        }
+        CACHE.with(|cache| {
+            // the cache is only accessed by the current thread and the access is not reentrant
+            let cache = unsafe { &mut *cache.get() };
+            if cache.flush_all.is_none() {
+                cache.owner = self.owner();
+                cache.flush_all = Some(Self::flush_all);
+            } else if cache.owner != self.owner() {
+                return None;
+            }
+            STATE.set(ThreadState::Busy);
+            let result = f(cache);
+            STATE.set(ThreadState::Active);
+            Some(result)
+        })
+    }
+
+    /// Allocate a batch of blocks in `bin` into the magazine, and return one of them.
+    fn refill(&self, magazine: &mut Magazine, bin: usize, layout: Layout) -> *mut u8 {
+        let Some(mut allocator) = self.inner.allocator() else {
+            return emergency::alloc(layout);
+        };
+        let Mimalloc {
+            heap,
+            os_alloc,
+            #[cfg(feature = "deferred_free")]
+            deferred_free_hook,
+            ..
+        } = &mut *allocator;
+        for _ in 0..BATCH_SIZE {
+            let Some((block, _)) = heap.malloc(
+                BLOCK_SIZE_FOR_BIN[bin],
+                os_alloc,
+                #[cfg(feature = "deferred_free")]
+                *deferred_free_hook,
+            ) else {
+                break;
+            };
+            magazine.push(block.as_ptr());
+        }
+        magazine.pop().unwrap_or(null_mut())
+    }
+
+    /// Free `count` blocks in the magazine.
+    fn flush(&self, magazine: &mut Magazine, count: usize) {
+        let Some(mut allocator) = self.inner.allocator() else {
+            return;
+        };
+        let Mimalloc { heap, os_alloc, .. } = &mut *allocator;
+        for _ in 0..count {
+            match magazine.pop() {
+                Some(block) => heap.free(block, os_alloc),
+                None => break,
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `owner` must be the owner of `cache`.
+    unsafe fn flush_all(owner: *const (), cache: &mut ThreadCache) {
+        let wrapper = &*(owner as *const Self);
+        for magazine in &mut cache.magazines {
+            if magazine.count > 0 {
+                wrapper.flush(magazine, magazine.count);
+            }
+        }
+    }
+}
+
+/// Whether blocks of this layout are cached.
+fn is_cached(layout: Layout) -> bool {
+    // blocks allocated with larger alignments may come from a larger bin, and cached blocks
+    // bypass the checks of `secure`: their links are not encoded, double frees are not detected
+    // and they do not have the padding canary
+    !cfg!(feature = "secure")
+        && layout.size() <= MI_SMALL_SIZE_MAX
+        && layout.align() <= MI_INTPTR_SIZE
+}
+
+/// Whether `ptr` is a guarded allocation, which is released by the heap instead.
+#[cfg(feature = "guarded")]
+fn is_guarded(ptr: *mut u8) -> bool {
+    unsafe { &*Segment::of_ptr(ptr) }.guarded().is_some()
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for MimallocCacheWrapper<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "guarded")]
+        let sampling = self.guarded.load(Ordering::Relaxed);
+        #[cfg(not(feature = "guarded"))]
+        let sampling = false;
+        if is_cached(layout) && !sampling {
+            let bin = bin_for_size(layout.size());
+            let result = self.with_cache(|cache| {
+                let magazine = &mut cache.magazines[bin];
+                let p = magazine.pop();
+                #[cfg(all(feature = "fill_on_free", debug_assertions))]
+                if let Some(p) = p {
+                    check_free_fill(p, BLOCK_SIZE_FOR_BIN[bin]);
+                }
+                p.unwrap_or_else(|| self.refill(magazine, bin, layout))
+            });
+            if let Some(p) = result {
+                return p;
+            }
+        }
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if is_cached(layout) {
+            // cached blocks are small and usually used before
+            let p = self.alloc(layout);
+            if !p.is_null() {
+                p.write_bytes(0, layout.size());
+            }
+            return p;
+        }
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if emergency::contains(ptr) {
+            return;
+        }
+        #[cfg(feature = "segment_map")]
+        if segment_map::is_foreign(ptr) {
+            return self.inner.dealloc(ptr, layout);
+        }
+        #[cfg(feature = "guarded")]
+        if is_guarded(ptr) {
+            return self.inner.dealloc(ptr, layout);
+        }
+        if is_cached(layout) {
+            let bin = bin_for_size(layout.size());
+            let result = self.with_cache(|cache| {
+                let magazine = &mut cache.magazines[bin];
+                if magazine.count >= MAGAZINE_SIZE {
+                    self.flush(magazine, BATCH_SIZE);
+                }
+                #[cfg(any(feature = "zero_on_free", feature = "fill_on_free"))]
+                fill_free(ptr, BLOCK_SIZE_FOR_BIN[bin]);
+                magazine.push(ptr);
+            });
+            if result.is_some() {
+                return;
+            }
+        }
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, false)
+    }
+}
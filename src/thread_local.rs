@@ -0,0 +1,210 @@
+use crate::heap::{Heap, HeapOptions};
+use crate::realloc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::{Cell, UnsafeCell};
+#[cfg(feature = "guarded")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Uninit,
+    Active,
+    /// The thread is exiting and its heap has been abandoned.
+    Exited,
+}
+
+/// Abandon the heap when the thread exits.
+struct ExitGuard;
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        STATE.set(ThreadState::Exited);
+        HEAP.with(|heap| unsafe { &mut *heap.get() }.abandon());
+    }
+}
+
+/// The heap shared by threads that allocate after their own heap is abandoned,
+/// e.g. in other thread-local destructors.
+struct ExitedHeap {
+    heap: Heap,
+    /// The options have been applied.
+    init: bool,
+}
+
+// the heap is only accessed while holding the lock
+unsafe impl Send for ExitedHeap {}
+
+static EXITED_HEAP: Mutex<ExitedHeap> = Mutex::new(ExitedHeap {
+    heap: Heap::new(),
+    init: false,
+});
+
+std::thread_local! {
+    // `HEAP` and `STATE` do not need drop, so they are still accessible when other
+    // thread-local destructors run
+    static HEAP: UnsafeCell<Heap> = const { UnsafeCell::new(Heap::new()) };
+    static STATE: Cell<ThreadState> = const { Cell::new(ThreadState::Uninit) };
+    static EXIT_GUARD: ExitGuard = const { ExitGuard };
+}
+
+/// Give each thread its own heap and implement [`GlobalAlloc`].
+///
+/// Allocation and local free do not need any lock.
+/// Blocks freed by other threads are pushed atomically and collected by the owning thread later.
+///
+/// When a thread exits, its segments are abandoned and reclaimed by the next thread that needs
+/// more memory. Blocks in them are still valid and can be freed by any thread. Later calls on the
+/// exiting thread, e.g. from other thread-local destructors, use a global heap behind a lock.
+///
+/// The thread-local heaps are shared by all instances,
+/// so only one instance should be used, typically as the [`global_allocator`].
+#[derive(Default)]
+pub struct MimallocThreadLocal<A: GlobalAlloc> {
+    os_alloc: A,
+    /// Applied to the heap of each thread when it is first used.
+    options: HeapOptions,
+    /// Read by the heap of each thread when it is used.
+    #[cfg(feature = "guarded")]
+    guarded_sample_rate: AtomicUsize,
+}
+
+impl<A: GlobalAlloc> MimallocThreadLocal<A> {
+    /// Create a new [`MimallocThreadLocal`] instance with an OS allocator.
+    pub const fn with_os_allocator(os_alloc: A) -> Self {
+        Self {
+            os_alloc,
+            options: HeapOptions::new(),
+            #[cfg(feature = "guarded")]
+            guarded_sample_rate: AtomicUsize::new(0),
+        }
+    }
+
+    /// See [`Mimalloc::with_zeroed_os_allocator`](crate::Mimalloc::with_zeroed_os_allocator).
+    pub const fn with_zeroed_os_allocator(os_alloc: A) -> Self
+    where
+        A: crate::ZeroedAlloc,
+    {
+        Self {
+            os_alloc,
+            options: HeapOptions::new().with_os_zero(),
+            #[cfg(feature = "guarded")]
+            guarded_sample_rate: AtomicUsize::new(0),
+        }
+    }
+
+    /// See [`Mimalloc::with_guard_pages`](crate::Mimalloc::with_guard_pages).
+    #[cfg(feature = "guard_pages")]
+    pub const fn with_guard_pages(os_alloc: A) -> Self
+    where
+        A: crate::ProtectAlloc,
+    {
+        Self {
+            os_alloc,
+            options: HeapOptions::new().with_guard_pages::<A>(),
+            #[cfg(feature = "guarded")]
+            guarded_sample_rate: AtomicUsize::new(0),
+        }
+    }
+
+    /// See [`Mimalloc::set_guarded_sample_rate`](crate::Mimalloc::set_guarded_sample_rate).
+    /// The heap of each thread picks up the new rate the next time it is used.
+    #[cfg(feature = "guarded")]
+    pub fn set_guarded_sample_rate(&self, rate: usize) {
+        self.guarded_sample_rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Collect free memory of the current thread and reclaim abandoned segments.
+    pub fn collect(&self) {
+        self.with_heap(|heap| heap.collect(&self.os_alloc));
+    }
+
+    /// See [`Mimalloc::usable_size`](crate::Mimalloc::usable_size).
+    /// It does not access the heap of the current thread.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mimalloc::usable_size`](crate::Mimalloc::usable_size).
+    pub unsafe fn usable_size(&self, ptr: *const u8) -> usize {
+        Heap::usable_size(ptr)
+    }
+
+    /// See [`Mimalloc::realloc_zeroed`](crate::Mimalloc::realloc_zeroed).
+    ///
+    /// # Safety
+    ///
+    /// See [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, true)
+    }
+
+    fn with_heap<T>(&self, f: impl FnOnce(&mut Heap) -> T) -> T {
+        if STATE.get() == ThreadState::Uninit {
+            STATE.set(ThreadState::Active);
+            HEAP.with(|heap| unsafe { &mut *heap.get() }.set_options(self.options));
+            // registering the destructor may allocate, so the heap must not be borrowed here
+            if EXIT_GUARD.try_with(|_| {}).is_err() {
+                STATE.set(ThreadState::Exited);
+            }
+        }
+        if STATE.get() == ThreadState::Exited {
+            // the heap of this thread is abandoned and no destructor will abandon it again
+            let mut exited = EXITED_HEAP.lock().unwrap_or_else(PoisonError::into_inner);
+            if !exited.init {
+                exited.init = true;
+                exited.heap.set_options(self.options);
+            }
+            return f(self.sync_heap(&mut exited.heap));
+        }
+        // the heap is only accessed by the current thread and the access is not reentrant
+        HEAP.with(|heap| f(self.sync_heap(unsafe { &mut *heap.get() })))
+    }
+
+    /// Apply the settings that can change after the heap is initialized.
+    fn sync_heap<'a>(&self, heap: &'a mut Heap) -> &'a mut Heap {
+        #[cfg(feature = "guarded")]
+        {
+            let rate = self.guarded_sample_rate.load(Ordering::Relaxed);
+            if heap.guarded_sample_rate() != rate {
+                heap.set_guarded_sample_rate(rate);
+            }
+        }
+        heap
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for MimallocThreadLocal<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| {
+            heap.malloc_aligned(
+                layout.size(),
+                layout.align(),
+                false,
+                &self.os_alloc,
+                #[cfg(feature = "deferred_free")]
+                None,
+            )
+        })
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| {
+            heap.malloc_aligned(
+                layout.size(),
+                layout.align(),
+                true,
+                &self.os_alloc,
+                #[cfg(feature = "deferred_free")]
+                None,
+            )
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _: Layout) {
+        self.with_heap(|heap| heap.free_mt(ptr, &self.os_alloc))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        realloc::realloc(self, ptr, layout, new_size, false)
+    }
+}
@@ -66,3 +66,49 @@ pub const WSIZE_RANGE_IN_SAME_SMALL_BIN: [(u8, u8); MI_SMALL_WSIZE_MAX + 1] =
     wsize_range_in_same_small_bin();
 
 pub const BLOCK_SIZE_FOR_BIN: [usize; MI_BIN_HUGE] = block_size_for_bin();
+
+// mi_good_size
+/// The size that an allocation of `size` bytes is rounded up to, i.e. the smallest
+/// [size class](size_classes) that fits it. Allocating this size instead wastes nothing.
+///
+/// Sizes beyond the largest size class are rounded up to a multiple of the word size.
+/// Alignments larger than the word size may need more.
+pub const fn good_size(size: usize) -> usize {
+    #[cfg(feature = "padding")]
+    let Some(padded_size) = size.checked_add(MI_PADDING_SIZE) else {
+        return size;
+    };
+    #[cfg(not(feature = "padding"))]
+    let padded_size = size;
+    let block_size = if padded_size <= MI_LARGE_SIZE_MAX {
+        BLOCK_SIZE_FOR_BIN[bin_for_size(padded_size)]
+    } else {
+        match padded_size.checked_next_multiple_of(MI_INTPTR_SIZE) {
+            Some(block_size) => block_size,
+            None => return size,
+        }
+    };
+    #[cfg(feature = "padding")]
+    let block_size = block_size - MI_PADDING_SIZE;
+    block_size
+}
+
+/// The sizes of the size classes in increasing order. Allocations of at most the largest size
+/// are served from blocks of the smallest size class that fits them.
+///
+/// With the `padding` feature, the padding is excluded from the sizes.
+pub fn size_classes() -> impl Iterator<Item = usize> {
+    (1..MI_BIN_HUGE).filter_map(|bin| {
+        let block_size = BLOCK_SIZE_FOR_BIN[bin];
+        // some small bins are skipped, depending on `MI_ALIGN_W`
+        if bin_for_size(block_size) != bin {
+            return None;
+        }
+        // blocks too small for the padding are never used
+        #[cfg(feature = "padding")]
+        let block_size = block_size
+            .checked_sub(MI_PADDING_SIZE)
+            .filter(|&size| size > 0)?;
+        Some(block_size)
+    })
+}
@@ -0,0 +1,156 @@
+#[cfg(all(
+    feature = "thread_cache",
+    any(feature = "std_mutex", feature = "spin_mutex")
+))]
+use baby_mimalloc::MimallocCacheWrapper;
+#[cfg(feature = "critical_section")]
+use baby_mimalloc::MimallocCriticalSection;
+#[cfg(feature = "thread_local")]
+use baby_mimalloc::MimallocThreadLocal;
+use baby_mimalloc::{Mimalloc, MimallocCell, ZeroedAlloc};
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use baby_mimalloc::{MimallocMutexWrapper, MimallocShardedWrapper};
+use std::alloc::{GlobalAlloc, Layout, System};
+
+/// OS allocator that returns zeroed memory from [`System`].
+struct ZeroedSystem;
+
+unsafe impl GlobalAlloc for ZeroedSystem {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+unsafe impl ZeroedAlloc for ZeroedSystem {}
+
+/// OS allocator that claims to return zeroed memory but fills it with [`DIRTY`],
+/// to tell which bytes are cleared by the allocator.
+#[derive(Clone, Copy)]
+struct DirtySystem;
+
+const DIRTY: u8 = 0xAA;
+
+unsafe impl GlobalAlloc for DirtySystem {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            p.write_bytes(DIRTY, layout.size());
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+unsafe impl ZeroedAlloc for DirtySystem {}
+
+fn is_zero(p: *mut u8, size: usize) -> bool {
+    unsafe { std::slice::from_raw_parts(p, size) }
+        .iter()
+        .all(|&byte| byte == 0)
+}
+
+fn alloc_zeroed_reused<A: GlobalAlloc>(mut allocator: Mimalloc<A>) {
+    for round in 0..2 {
+        let allocation = Vec::from_iter((0..2000).map(|i| {
+            let size = 1 + i * 37 % 5000;
+            let align = 1 << (i % 8);
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let p = unsafe { allocator.alloc_zeroed(layout) };
+            assert!((p as usize).is_multiple_of(align));
+            assert!(is_zero(p, size), "round {round}, size {size}");
+            unsafe { p.write_bytes(0xff, size) };
+            (p, layout)
+        }));
+        for (p, layout) in allocation {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+    }
+    let huge = Layout::from_size_align(10 << 20, 8).unwrap();
+    for _ in 0..2 {
+        let p = unsafe { allocator.alloc_zeroed(huge) };
+        assert!(is_zero(p, huge.size()));
+        unsafe { p.write_bytes(0xff, huge.size()) };
+        unsafe { allocator.dealloc(p, huge) };
+    }
+}
+
+#[test]
+fn zeroed_os_allocator() {
+    alloc_zeroed_reused(Mimalloc::with_zeroed_os_allocator(ZeroedSystem));
+}
+
+#[test]
+fn dirty_os_allocator() {
+    alloc_zeroed_reused(Mimalloc::with_os_allocator(DirtySystem));
+}
+
+/// Check that only the free list link of a fresh block from [`DirtySystem`] is cleared.
+fn assert_fresh_block_not_cleared(p: *mut u8, size: usize) {
+    let bytes = unsafe { std::slice::from_raw_parts(p, size) };
+    let word = size_of::<usize>();
+    assert!(bytes[..word].iter().all(|&byte| byte == 0));
+    #[cfg(not(all(feature = "fill_on_free", debug_assertions)))]
+    assert!(bytes[word..].iter().all(|&byte| byte == DIRTY));
+}
+
+#[test]
+fn fresh_blocks_not_cleared() {
+    let mut allocator = Mimalloc::with_zeroed_os_allocator(DirtySystem);
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let p = unsafe { allocator.alloc_zeroed(layout) };
+    assert_fresh_block_not_cleared(p, layout.size());
+    unsafe { allocator.dealloc(p, layout) };
+}
+
+#[test]
+fn wrappers_with_zeroed_os_allocator() {
+    fn check<G: GlobalAlloc>(allocator: G) {
+        // not cached by `MimallocCacheWrapper`
+        let layout = Layout::from_size_align(2000, 8).unwrap();
+        let p = unsafe { allocator.alloc_zeroed(layout) };
+        assert_fresh_block_not_cleared(p, layout.size());
+        unsafe { allocator.dealloc(p, layout) };
+    }
+
+    check(unsafe { MimallocCell::with_zeroed_os_allocator(DirtySystem) });
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    {
+        check(MimallocMutexWrapper::with_zeroed_os_allocator(DirtySystem));
+        check(MimallocShardedWrapper::<_, 2>::with_zeroed_os_allocator(
+            DirtySystem,
+        ));
+    }
+    #[cfg(all(
+        feature = "thread_cache",
+        any(feature = "std_mutex", feature = "spin_mutex")
+    ))]
+    check(MimallocCacheWrapper::with_zeroed_os_allocator(DirtySystem));
+    #[cfg(feature = "critical_section")]
+    check(MimallocCriticalSection::with_zeroed_os_allocator(
+        DirtySystem,
+    ));
+    // in a new thread, whose heap is set up by this instance
+    #[cfg(feature = "thread_local")]
+    std::thread::spawn(|| check(MimallocThreadLocal::with_zeroed_os_allocator(DirtySystem)))
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn global_alloc_zeroed() {
+    let allocator = unsafe { MimallocCell::with_os_allocator(ZeroedSystem) };
+    let layout = Layout::from_size_align(3000, 64).unwrap();
+    for _ in 0..100 {
+        let p = unsafe { allocator.alloc_zeroed(layout) };
+        assert!(is_zero(p, layout.size()));
+        unsafe { p.write_bytes(0xff, layout.size()) };
+        unsafe { allocator.dealloc(p, layout) };
+    }
+}
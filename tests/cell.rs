@@ -0,0 +1,62 @@
+#[cfg(debug_assertions)]
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::MimallocCell;
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+#[test]
+fn single_thread_alloc() {
+    let allocator = unsafe { MimallocCell::with_os_allocator(System) };
+    let allocation = Vec::from_iter((1..100_000).map(|size| {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let p = unsafe { allocator.alloc(layout) };
+        assert!((p as usize).is_multiple_of(8));
+        unsafe { p.write_bytes(0x37, size) };
+        (p, layout)
+    }));
+    for (p, layout) in allocation {
+        unsafe { allocator.dealloc(p, layout) };
+    }
+    allocator.collect();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn reentrant_alloc() {
+    /// OS allocator that allocates from [`REENTRANT`] again.
+    struct ReentrantAlloc;
+
+    /// The result of the last nested allocation.
+    static NESTED: AtomicPtr<u8> = AtomicPtr::new(std::ptr::dangling_mut());
+
+    unsafe impl GlobalAlloc for ReentrantAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let nested = REENTRANT.alloc(Layout::new::<usize>());
+            NESTED.store(nested, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    static REENTRANT: MimallocCell<ReentrantAlloc> =
+        unsafe { MimallocCell::with_os_allocator(ReentrantAlloc) };
+
+    static REENTRANT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    fn error_handler(error: Error) {
+        assert_eq!(error, Error::Reentrant);
+        REENTRANT_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+
+    register_error(error_handler);
+    let layout = Layout::new::<usize>();
+    let p = unsafe { REENTRANT.alloc(layout) };
+    assert!(!p.is_null());
+    assert!(NESTED.load(Ordering::Relaxed).is_null());
+    assert_eq!(REENTRANT_COUNT.load(Ordering::Relaxed), 1);
+    unsafe { REENTRANT.dealloc(p, layout) };
+}
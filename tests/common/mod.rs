@@ -0,0 +1,65 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use rand::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+static USED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// [`System`] that records the memory allocated through it.
+#[derive(Clone, Copy)]
+pub struct SystemWithStat;
+
+impl SystemWithStat {
+    /// The memory currently allocated.
+    pub fn used() -> usize {
+        USED.load(Ordering::Relaxed)
+    }
+
+    /// The maximum memory allocated at the same time.
+    pub fn peak() -> usize {
+        PEAK.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl GlobalAlloc for SystemWithStat {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let used = USED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(used, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        USED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Allocate and check vectors of random lengths below `max_len` in `threads` threads.
+pub fn local_alloc(threads: usize, max_len: usize) {
+    let handles = Vec::from_iter((0..threads).map(|_| {
+        thread::spawn(move || {
+            let mut rng = thread_rng();
+            for _ in 0..10 {
+                let vec =
+                    Vec::from_iter((0..10_000).map(|_| vec![42u8; rng.gen_range(1..max_len)]));
+                assert!(vec.iter().all(|v| v.iter().all(|&x| x == 42)));
+            }
+        })
+    }));
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// Hold the returned guard to not run in parallel with other tests holding it,
+/// e.g. when measuring the memory of the global allocator.
+pub fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use baby_mimalloc::MimallocCriticalSection;
+use common::SystemWithStat;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ptr::{dangling_mut, null_mut};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+#[global_allocator]
+static ALLOCATOR: MimallocCriticalSection<System> =
+    MimallocCriticalSection::with_os_allocator(System);
+
+#[test]
+fn multi_thread_alloc() {
+    common::local_alloc(4, 10_000);
+
+    ALLOCATOR.collect();
+}
+
+/// Larger than a segment, so that it is returned to the OS allocator when collected.
+const HUGE: Layout = unsafe { Layout::from_size_align_unchecked(8 << 20, 8) };
+
+/// OS allocator that uses [`REENTRANT`] again, inside its critical section.
+struct ReentrantAlloc;
+
+/// The result of the last nested allocation.
+static NESTED: AtomicPtr<u8> = AtomicPtr::new(dangling_mut());
+/// A [`HUGE`] block to free in the next nested call.
+static PENDING: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+
+unsafe impl GlobalAlloc for ReentrantAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        NESTED.store(REENTRANT.alloc(Layout::new::<usize>()), Ordering::Relaxed);
+        let pending = PENDING.swap(null_mut(), Ordering::Relaxed);
+        if !pending.is_null() {
+            REENTRANT.dealloc(pending, HUGE);
+        }
+        SystemWithStat.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        SystemWithStat.dealloc(ptr, layout)
+    }
+}
+
+static REENTRANT: MimallocCriticalSection<ReentrantAlloc> =
+    MimallocCriticalSection::with_os_allocator(ReentrantAlloc);
+
+#[test]
+fn reentrant() {
+    // the nested allocation returns null
+    let huge = unsafe { REENTRANT.alloc(HUGE) };
+    assert!(!huge.is_null());
+    assert!(NESTED.load(Ordering::Relaxed).is_null());
+
+    // the nested deallocation leaks the block
+    PENDING.store(huge, Ordering::Relaxed);
+    let used = SystemWithStat::used();
+    let p = unsafe { REENTRANT.alloc(HUGE) };
+    assert!(!p.is_null());
+    assert!(PENDING.load(Ordering::Relaxed).is_null());
+    REENTRANT.collect();
+    let leaked = SystemWithStat::used();
+    assert!(
+        leaked >= used + HUGE.size(),
+        "used: {used}, leaked: {leaked}"
+    );
+
+    // the leaked block can still be freed
+    unsafe { REENTRANT.dealloc(huge, HUGE) };
+    unsafe { REENTRANT.dealloc(p, HUGE) };
+    REENTRANT.collect();
+    assert!(SystemWithStat::used() < used);
+}
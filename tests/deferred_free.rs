@@ -11,7 +11,10 @@ fn test_alloc<A: GlobalAlloc>(
 ) -> (*mut u8, Layout) {
     let layout = Layout::from_size_align(size, align).unwrap();
     let p = unsafe { allocator.alloc(layout) };
-    assert!(p as usize % align == 0, "p: {p:?}, align: {align}");
+    assert!(
+        (p as usize).is_multiple_of(align),
+        "p: {p:?}, align: {align}"
+    );
     unsafe { p.write_bytes(0x37, size) };
     (p, layout)
 }
@@ -24,7 +27,7 @@ static DEFERRED_FREE_ALLOCATOR: Mutex<Mimalloc<System>> = {
 };
 
 fn deferred_free_hook(handle: &mut DeferredFreeHandle<System>, force: bool, heartbeat: u64) {
-    if heartbeat % 10000 == 0 {
+    if heartbeat.is_multiple_of(10000) {
         dbg!(force, heartbeat);
     }
     for (addr, _) in DEFERRED_FREE_ALLOCATION.lock().unwrap().drain(..) {
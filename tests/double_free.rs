@@ -0,0 +1,115 @@
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::Mimalloc;
+#[cfg(all(
+    feature = "thread_cache",
+    any(feature = "std_mutex", feature = "spin_mutex")
+))]
+use baby_mimalloc::MimallocCacheWrapper;
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use baby_mimalloc::MimallocMutexWrapper;
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use std::alloc::GlobalAlloc;
+use std::alloc::{Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    // errors are reported on the thread that detects them, so tests can run in parallel
+    static DOUBLE_FREE_COUNT: Cell<usize> = const { Cell::new(0) };
+}
+
+fn error_handler(error: Error) {
+    assert!(matches!(error, Error::DoubleFree { .. }));
+    DOUBLE_FREE_COUNT.set(DOUBLE_FREE_COUNT.get() + 1);
+}
+
+#[test]
+fn double_free() {
+    register_error(error_handler);
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    for (size, align) in [(8, 8), (100, 8), (100, 64), (10_000, 8)] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let count = DOUBLE_FREE_COUNT.get();
+        let blocks = Vec::from_iter((0..100).map(|_| unsafe { allocator.alloc(layout) }));
+        for &p in &blocks {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+        assert_eq!(DOUBLE_FREE_COUNT.get(), count);
+
+        let p = unsafe { allocator.alloc(layout) };
+        let q = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(p, layout) };
+        unsafe { allocator.dealloc(p, layout) };
+        assert_eq!(DOUBLE_FREE_COUNT.get(), count + 1);
+
+        // the block is not handed out twice
+        let r = unsafe { allocator.alloc(layout) };
+        let s = unsafe { allocator.alloc(layout) };
+        assert_ne!(r, s);
+        for p in [q, r, s] {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+        assert_eq!(DOUBLE_FREE_COUNT.get(), count + 1);
+    }
+}
+
+#[test]
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+fn double_free_remote() {
+    register_error(error_handler);
+    let allocator = MimallocMutexWrapper::with_os_allocator(System);
+    for (size, align) in [(8, 8), (100, 8), (100, 64), (10_000, 8)] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let count = DOUBLE_FREE_COUNT.get();
+        let blocks = Vec::from_iter((0..100).map(|_| unsafe { allocator.alloc(layout) }));
+
+        // the wrapper frees without the lock, so both frees are pushed to the page
+        let (p, blocks) = blocks.split_first().unwrap();
+        unsafe { allocator.dealloc(*p, layout) };
+        unsafe { allocator.dealloc(*p, layout) };
+        for &q in blocks {
+            unsafe { allocator.dealloc(q, layout) };
+        }
+
+        // the owner detects the cycle when it collects the freed blocks
+        allocator.collect();
+        assert_eq!(DOUBLE_FREE_COUNT.get(), count + 1);
+
+        let r = unsafe { allocator.alloc(layout) };
+        let s = unsafe { allocator.alloc(layout) };
+        assert_ne!(r, s);
+        for p in [r, s] {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+        assert_eq!(DOUBLE_FREE_COUNT.get(), count + 1);
+    }
+}
+
+#[test]
+#[cfg(all(
+    feature = "thread_cache",
+    any(feature = "std_mutex", feature = "spin_mutex")
+))]
+fn double_free_cached() {
+    static ALLOCATOR: MimallocCacheWrapper<System> =
+        MimallocCacheWrapper::with_os_allocator(System);
+
+    register_error(error_handler);
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    let count = DOUBLE_FREE_COUNT.get();
+    let p = unsafe { ALLOCATOR.alloc(layout) };
+    let q = unsafe { ALLOCATOR.alloc(layout) };
+    // small blocks bypass the cache, so the second free is detected
+    unsafe { ALLOCATOR.dealloc(p, layout) };
+    unsafe { ALLOCATOR.dealloc(p, layout) };
+    ALLOCATOR.collect();
+    assert_eq!(DOUBLE_FREE_COUNT.get(), count + 1);
+
+    // the block is not handed out twice
+    let r = unsafe { ALLOCATOR.alloc(layout) };
+    let s = unsafe { ALLOCATOR.alloc(layout) };
+    assert_ne!(r, s);
+    for p in [q, r, s] {
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+    }
+    assert_eq!(DOUBLE_FREE_COUNT.get(), count + 1);
+}
@@ -0,0 +1,43 @@
+use baby_mimalloc::MimallocMutexWrapper;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// [`System`] that counts deallocations.
+struct CountingSystem(AtomicUsize);
+
+unsafe impl GlobalAlloc for CountingSystem {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+static FALLBACK: CountingSystem = CountingSystem(AtomicUsize::new(0));
+
+#[global_allocator]
+static ALLOCATOR: MimallocMutexWrapper<System> = MimallocMutexWrapper::with_os_allocator(System);
+
+#[test]
+fn forward_foreign_pointers() {
+    ALLOCATOR.register_fallback(&FALLBACK);
+
+    let vec = Vec::from_iter((0..1000).map(|i| vec![i; i]));
+    drop(vec);
+    assert_eq!(FALLBACK.0.load(Ordering::Relaxed), 0);
+
+    // allocated before switching the global allocator
+    let boxed = unsafe {
+        let p = System
+            .alloc(Layout::new::<[u64; 100]>())
+            .cast::<[u64; 100]>();
+        p.write([42; 100]);
+        Box::from_raw(p)
+    };
+    assert!(boxed.iter().all(|&x| x == 42));
+    drop(boxed);
+    assert_eq!(FALLBACK.0.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,39 @@
+use baby_mimalloc::Mimalloc;
+use std::alloc::{Layout, System};
+
+#[test]
+fn fill_on_free() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    for size in [8, 100, 10_000] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let blocks = Vec::from_iter((0..100).map(|_| unsafe { allocator.alloc(layout) }));
+        for &p in &blocks {
+            unsafe { p.write_bytes(0xAA, size) };
+        }
+        for &p in &blocks[1..] {
+            unsafe { allocator.dealloc(p, layout) };
+            let bytes = unsafe { std::slice::from_raw_parts(p, size) };
+            assert!(bytes[size_of::<usize>()..].iter().all(|&byte| byte == 0xDF));
+        }
+        // reuse the freed blocks
+        for p in Vec::from_iter((1..100).map(|_| unsafe { allocator.alloc(layout) })) {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+        unsafe { allocator.dealloc(blocks[0], layout) };
+    }
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "block written after free")]
+fn write_after_free() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let p = unsafe { allocator.alloc(layout) };
+    unsafe { allocator.dealloc(p, layout) };
+    unsafe { p.add(50).write(42) };
+    // allocate until the block is reused
+    for _ in 0..10_000 {
+        unsafe { allocator.alloc(layout) };
+    }
+}
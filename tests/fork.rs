@@ -0,0 +1,84 @@
+use baby_mimalloc::{new_mimalloc_mmap_mutex, MimallocMmapMutex};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+#[global_allocator]
+static ALLOCATOR: MimallocMmapMutex = new_mimalloc_mmap_mutex();
+
+#[test]
+fn fork_while_allocating() {
+    assert!(ALLOCATOR.register_fork_handlers());
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let allocating = thread::spawn(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            black_box(vec![42u8; 1000]);
+        }
+    });
+
+    for _ in 0..100 {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // the allocator lock must not be held by the allocating thread in the child
+            let vec = black_box(vec![42u8; 1000]);
+            let code = if vec.iter().all(|&x| x == 42) { 0 } else { 1 };
+            unsafe { libc::_exit(code) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    STOP.store(true, Ordering::Relaxed);
+    allocating.join().unwrap();
+}
+
+#[test]
+fn fork_while_freeing() {
+    assert!(ALLOCATOR.register_fork_handlers());
+
+    // blocks of full pages are freed by another thread without the lock
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let (sender, receiver) = sync_channel::<Vec<Box<[u8; 48]>>>(16);
+    let allocating = thread::spawn(move || {
+        while !STOP.load(Ordering::Relaxed) {
+            let blocks = Vec::from_iter((0..1000).map(|_| Box::new([42u8; 48])));
+            if sender.send(blocks).is_err() {
+                break;
+            }
+        }
+    });
+    let freeing = thread::spawn(move || {
+        for blocks in receiver {
+            drop(blocks);
+        }
+    });
+
+    for _ in 0..100 {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // a free interrupted by `fork` must not block the child
+            unsafe { libc::alarm(10) };
+            let blocks = Vec::from_iter((0..100_000).map(|_| black_box(Box::new([42u8; 48]))));
+            let code = if blocks.iter().all(|b| b[0] == 42) {
+                0
+            } else {
+                1
+            };
+            drop(blocks);
+            ALLOCATOR.collect();
+            unsafe { libc::_exit(code) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    STOP.store(true, Ordering::Relaxed);
+    allocating.join().unwrap();
+    freeing.join().unwrap();
+}
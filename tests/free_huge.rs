@@ -0,0 +1,72 @@
+mod common;
+
+use baby_mimalloc::MimallocMutexWrapper;
+use common::SystemWithStat;
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+const HUGE: usize = 64 * 1024 * 1024;
+
+/// A huge block to be freed by [`FreeOnAlloc`].
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// [`SystemWithStat`] that frees the pending huge block while the allocator is locked.
+struct FreeOnAlloc;
+
+unsafe impl GlobalAlloc for FreeOnAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = PENDING.swap(0, Ordering::Relaxed);
+        if p != 0 {
+            ALLOCATOR.dealloc(p as _, huge_layout());
+        }
+        SystemWithStat.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        SystemWithStat.dealloc(ptr, layout)
+    }
+}
+
+static ALLOCATOR: MimallocMutexWrapper<FreeOnAlloc> =
+    MimallocMutexWrapper::with_os_allocator(FreeOnAlloc);
+
+fn huge_layout() -> Layout {
+    Layout::from_size_align(HUGE, 8).unwrap()
+}
+
+#[test]
+fn free_huge() {
+    let _serial = common::serial();
+    let p = unsafe { ALLOCATOR.alloc(huge_layout()) } as usize;
+    let used = SystemWithStat::used();
+    assert!(used >= HUGE, "used: {used} < {HUGE}");
+
+    // the lock is available, so the block is returned to the OS allocator right away
+    thread::spawn(move || unsafe { ALLOCATOR.dealloc(p as _, huge_layout()) })
+        .join()
+        .unwrap();
+    let used = SystemWithStat::used();
+    assert!(used < HUGE, "used: {used} >= {HUGE}");
+}
+
+#[test]
+fn free_huge_while_locked() {
+    let _serial = common::serial();
+    let p = unsafe { ALLOCATOR.alloc(huge_layout()) };
+    PENDING.store(p as usize, Ordering::Relaxed);
+
+    // `p` is freed while the lock is held to allocate `q`
+    let q = unsafe { ALLOCATOR.alloc(huge_layout()) };
+    assert_eq!(PENDING.load(Ordering::Relaxed), 0);
+    let used = SystemWithStat::used();
+    assert!(used >= 2 * HUGE, "used: {used} < {}", 2 * HUGE);
+
+    // the next lock holder returns it to the OS allocator
+    let layout = Layout::new::<u64>();
+    unsafe { ALLOCATOR.dealloc(ALLOCATOR.alloc(layout), layout) };
+    let used = SystemWithStat::used();
+    assert!(used < 2 * HUGE, "used: {used} >= {}", 2 * HUGE);
+
+    unsafe { ALLOCATOR.dealloc(q, huge_layout()) };
+}
@@ -42,3 +42,103 @@ fn btree_map_test() {
     let vec = map.into_iter().collect::<Vec<_>>();
     assert_eq!(map_len, vec.len());
 }
+
+#[test]
+fn cross_thread_free() {
+    extern crate std;
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+
+    const N: usize = 1_000_000;
+
+    let (sender, receiver) = sync_channel::<Vec<u8>>(1024);
+
+    let producers = Vec::from_iter((0..2).map(|_| {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut rng = thread_rng();
+            for _ in 0..N {
+                sender.send(vec![42; rng.gen_range(1..1024)]).unwrap();
+            }
+        })
+    }));
+    drop(sender);
+
+    let consumer = thread::spawn(move || {
+        for vec in receiver {
+            assert!(vec.iter().all(|&x| x == 42));
+        }
+    });
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    consumer.join().unwrap();
+}
+
+#[test]
+fn try_alloc() {
+    extern crate std;
+    use core::alloc::Layout;
+    use std::thread;
+
+    let handles = Vec::from_iter((0..4).map(|_| {
+        thread::spawn(|| {
+            let mut rng = thread_rng();
+            let mut allocation = Vec::new();
+            for _ in 0..100_000 {
+                let layout = Layout::from_size_align(rng.gen_range(1..1024), 8).unwrap();
+                let p = unsafe { ALLOCATOR.try_alloc(layout) };
+                if !p.is_null() {
+                    unsafe { p.write_bytes(0x37, layout.size()) };
+                    allocation.push((p, layout));
+                }
+            }
+            assert!(!allocation.is_empty());
+            for (p, layout) in allocation {
+                unsafe { ALLOCATOR.try_dealloc(p, layout) };
+            }
+        })
+    }));
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn try_alloc_reentrant() {
+    use baby_mimalloc::{MimallocMutexWrapper, MmapAlloc};
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::ptr::{dangling_mut, null_mut};
+    use core::sync::atomic::{AtomicPtr, Ordering};
+
+    /// OS allocator that allocates from [`REENTRANT`] again, which would deadlock without `std`
+    /// if it did not use `try_alloc`.
+    struct ReentrantAlloc;
+
+    /// The result of the last nested allocation.
+    static NESTED: AtomicPtr<u8> = AtomicPtr::new(dangling_mut());
+
+    unsafe impl GlobalAlloc for ReentrantAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            NESTED.store(
+                REENTRANT.try_alloc(Layout::new::<usize>()),
+                Ordering::Relaxed,
+            );
+            MmapAlloc.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            MmapAlloc.dealloc(ptr, layout)
+        }
+    }
+
+    static REENTRANT: MimallocMutexWrapper<ReentrantAlloc> =
+        MimallocMutexWrapper::with_os_allocator(ReentrantAlloc);
+
+    let layout = Layout::new::<usize>();
+    let p = unsafe { REENTRANT.alloc(layout) };
+    assert!(!p.is_null());
+    assert_eq!(NESTED.load(Ordering::Relaxed), null_mut());
+    unsafe { REENTRANT.dealloc(p, layout) };
+}
@@ -0,0 +1,39 @@
+use baby_mimalloc::{good_size, size_classes, Mimalloc};
+use std::alloc::{Layout, System};
+
+#[test]
+fn size_classes_are_good_sizes() {
+    let classes = Vec::from_iter(size_classes());
+    assert!(classes.len() > 40);
+    assert!(classes.is_sorted_by(|a, b| a < b));
+    assert_eq!(good_size(1), classes[0]);
+    for window in classes.windows(2) {
+        assert_eq!(good_size(window[0]), window[0]);
+        assert_eq!(good_size(window[0] + 1), window[1]);
+    }
+    let largest = *classes.last().unwrap();
+    assert_eq!(good_size(largest), largest);
+    assert_eq!(
+        good_size(largest + 1),
+        (largest + 1).next_multiple_of(size_of::<usize>())
+    );
+    assert_eq!(good_size(usize::MAX), usize::MAX);
+}
+
+#[test]
+fn good_size_is_usable_size() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    for size in (1..2_000_000).step_by(1013) {
+        let good = good_size(size);
+        assert!(good >= size);
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let p = unsafe { allocator.alloc(layout) };
+        // the usable size is the requested size with padding
+        #[cfg(not(feature = "padding"))]
+        assert_eq!(unsafe { allocator.usable_size(p) }, good, "size {size}");
+        // growing to the good size does not move the block
+        let q = unsafe { allocator.realloc(p, layout, good) };
+        assert_eq!(p, q, "size {size}");
+        unsafe { allocator.dealloc(q, Layout::from_size_align(good, 8).unwrap()) };
+    }
+}
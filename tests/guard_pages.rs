@@ -0,0 +1,145 @@
+#[cfg(all(
+    feature = "thread_cache",
+    any(feature = "std_mutex", feature = "spin_mutex")
+))]
+use baby_mimalloc::MimallocCacheWrapper;
+#[cfg(feature = "critical_section")]
+use baby_mimalloc::MimallocCriticalSection;
+#[cfg(feature = "thread_local")]
+use baby_mimalloc::MimallocThreadLocal;
+use baby_mimalloc::{Mimalloc, MimallocCell, MmapAlloc};
+#[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+use baby_mimalloc::{MimallocMutexWrapper, MimallocShardedWrapper};
+use std::alloc::{GlobalAlloc, Layout};
+use std::hint::black_box;
+
+const SMALL_PAGE_SIZE: usize = 8192 * size_of::<usize>();
+const SEGMENT_SIZE: usize = 64 * SMALL_PAGE_SIZE;
+
+/// Write from the start of a block to the end of the `region` containing it.
+fn overflow_faults(layout: Layout, region: usize) -> bool {
+    block_overflow_faults(
+        || {
+            let mut allocator = Mimalloc::with_guard_pages(MmapAlloc);
+            let p = unsafe { allocator.alloc(layout) };
+            std::mem::forget(allocator);
+            p
+        },
+        region,
+    )
+}
+
+/// [`overflow_faults`] with a block allocated by a wrapper that `new` returns.
+fn wrapper_overflow_faults<G: GlobalAlloc>(
+    new: impl FnOnce() -> G,
+    layout: Layout,
+    region: usize,
+) -> bool {
+    block_overflow_faults(
+        || {
+            let allocator = new();
+            let p = unsafe { allocator.alloc(layout) };
+            std::mem::forget(allocator);
+            p
+        },
+        region,
+    )
+}
+
+/// Write from the start of the block that `alloc` returns to the end of the `region`
+/// containing it, in a child process.
+fn block_overflow_faults(alloc: impl FnOnce() -> *mut u8, region: usize) -> bool {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let p = alloc();
+        // the memory is mapped until the end of the region, so only a guard page can fault
+        let end = (p as usize + 1).next_multiple_of(region);
+        for addr in p as usize..end {
+            unsafe { black_box(addr as *mut u8).write_volatile(42) };
+        }
+        unsafe { libc::_exit(0) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+}
+
+#[test]
+fn small_page_overflow() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    assert!(overflow_faults(layout, SMALL_PAGE_SIZE));
+}
+
+#[test]
+fn large_page_overflow() {
+    let layout = Layout::from_size_align(100_000, 8).unwrap();
+    assert!(overflow_faults(layout, SEGMENT_SIZE));
+}
+
+#[test]
+fn huge_page_overflow() {
+    // the huge segment is rounded up to 1 MiB
+    let layout = Layout::from_size_align(1_000_000, 8).unwrap();
+    assert!(overflow_faults(layout, 1024 * 1024));
+}
+
+#[test]
+fn wrapper_small_page_overflow() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    assert!(wrapper_overflow_faults(
+        || unsafe { MimallocCell::with_guard_pages(MmapAlloc) },
+        layout,
+        SMALL_PAGE_SIZE
+    ));
+    #[cfg(any(feature = "std_mutex", feature = "spin_mutex"))]
+    {
+        assert!(wrapper_overflow_faults(
+            || MimallocMutexWrapper::with_guard_pages(MmapAlloc),
+            layout,
+            SMALL_PAGE_SIZE
+        ));
+        assert!(wrapper_overflow_faults(
+            || MimallocShardedWrapper::<_, 2>::with_guard_pages(MmapAlloc),
+            layout,
+            SMALL_PAGE_SIZE
+        ));
+    }
+    #[cfg(all(
+        feature = "thread_cache",
+        any(feature = "std_mutex", feature = "spin_mutex")
+    ))]
+    assert!(wrapper_overflow_faults(
+        || MimallocCacheWrapper::with_guard_pages(MmapAlloc),
+        layout,
+        SMALL_PAGE_SIZE
+    ));
+    #[cfg(feature = "critical_section")]
+    assert!(wrapper_overflow_faults(
+        || MimallocCriticalSection::with_guard_pages(MmapAlloc),
+        layout,
+        SMALL_PAGE_SIZE
+    ));
+    #[cfg(feature = "thread_local")]
+    assert!(wrapper_overflow_faults(
+        || MimallocThreadLocal::with_guard_pages(MmapAlloc),
+        layout,
+        SMALL_PAGE_SIZE
+    ));
+}
+
+#[test]
+fn fill_pages() {
+    let mut allocator = Mimalloc::with_guard_pages(MmapAlloc);
+    for size in [8, 64, 1000, 10_000, 100_000, 1_000_000] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let blocks = Vec::from_iter((0..200).map(|_| unsafe { allocator.alloc(layout) }));
+        for &p in &blocks {
+            assert!(!p.is_null());
+            unsafe { p.write_bytes(42, size) };
+        }
+        for &p in &blocks {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::{install_guarded_fault_handler, Mimalloc, MimallocCell, MmapAlloc};
+use std::alloc::{GlobalAlloc, Layout};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn os_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) as usize }
+}
+
+fn guarded_allocator(rate: usize) -> Mimalloc<MmapAlloc> {
+    let mut allocator = Mimalloc::with_guard_pages(MmapAlloc);
+    allocator.set_guarded_sample_rate(rate);
+    allocator
+}
+
+/// Whether `p` ends less than `align` bytes before a page boundary, as guarded allocations do.
+fn is_guarded(p: *mut u8, layout: Layout) -> bool {
+    let end = p as usize + layout.size();
+    end.next_multiple_of(os_page_size()) - end < layout.align()
+}
+
+static EXPECTED: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+fn fault_handler(error: Error) {
+    let Error::GuardedFault {
+        addr,
+        ptr,
+        size,
+        freed,
+    } = error
+    else {
+        unsafe { libc::_exit(1) };
+    };
+    let [expected_addr, expected_ptr, expected_size] = EXPECTED
+        .each_ref()
+        .map(|value| value.load(Ordering::Relaxed));
+    let code = if addr == expected_addr && ptr == expected_ptr && size == expected_size {
+        if freed {
+            43
+        } else {
+            42
+        }
+    } else {
+        2
+    };
+    unsafe { libc::_exit(code) };
+}
+
+/// Run `f` with a guarded allocation in a child process, and return its exit code or signal.
+fn run_child(layout: Layout, f: fn(*mut u8, &mut Mimalloc<MmapAlloc>, Layout) -> usize) -> i32 {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        register_error(fault_handler);
+        assert!(install_guarded_fault_handler());
+        let mut allocator = guarded_allocator(1);
+        let p = unsafe { allocator.alloc(layout) };
+        EXPECTED[1].store(p as usize, Ordering::Relaxed);
+        EXPECTED[2].store(layout.size(), Ordering::Relaxed);
+        let addr = f(p, &mut allocator, layout);
+        EXPECTED[0].store(addr, Ordering::Relaxed);
+        unsafe { black_box(addr as *mut u8).write_volatile(42) };
+        unsafe { libc::_exit(0) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        -libc::WTERMSIG(status)
+    }
+}
+
+#[test]
+fn sampling() {
+    let mut allocator = guarded_allocator(4);
+    for (size, align) in [
+        (8, 8),
+        (100, 8),
+        (100, 64),
+        (10_000, 8),
+        (10_000, 4096),
+        (1_000_000, 8),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let blocks = Vec::from_iter((0..100).map(|_| unsafe { allocator.alloc(layout) }));
+        let guarded = blocks.iter().filter(|&&p| is_guarded(p, layout)).count();
+        assert!(guarded >= 25, "size: {size}, guarded: {guarded}");
+        for &p in &blocks {
+            assert!(!p.is_null());
+            assert!((p as usize).is_multiple_of(align));
+            let usable_size = unsafe { allocator.usable_size(p) };
+            assert!(usable_size >= size);
+            unsafe { p.write_bytes(42, usable_size) };
+        }
+        for &p in &blocks {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+    }
+}
+
+#[test]
+fn sampling_disabled() {
+    let mut allocator = guarded_allocator(0);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let blocks = Vec::from_iter((0..100).map(|_| unsafe { allocator.alloc(layout) }));
+    let guarded = blocks.iter().filter(|&&p| is_guarded(p, layout)).count();
+    // only blocks that happen to end at a page boundary
+    assert!(guarded <= 5, "guarded: {guarded}");
+    for p in blocks {
+        unsafe { allocator.dealloc(p, layout) };
+    }
+}
+
+#[test]
+fn overflow() {
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    assert_eq!(
+        run_child(layout, |p, _, layout| p as usize + layout.size()),
+        42
+    );
+}
+
+#[test]
+fn underflow() {
+    let layout = Layout::from_size_align(10_000, 8).unwrap();
+    let code = run_child(layout, |p, _, _| {
+        // before the slot, which starts at a page boundary
+        (p as usize).next_multiple_of(os_page_size()) - os_page_size() - 1
+    });
+    assert_eq!(code, 42);
+}
+
+#[test]
+fn use_after_free() {
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let code = run_child(layout, |p, allocator, layout| {
+        unsafe { allocator.dealloc(p, layout) };
+        p as usize
+    });
+    assert_eq!(code, 43);
+}
+
+#[test]
+fn unrelated_fault() {
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    assert_eq!(run_child(layout, |_, _, _| 8), -libc::SIGSEGV);
+}
+
+#[test]
+fn stack_overflow() {
+    fn recurse(depth: usize) -> usize {
+        let frame = black_box([depth; 64]);
+        if black_box(true) {
+            recurse(frame[0] + 1) + frame[1]
+        } else {
+            0
+        }
+    }
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        assert!(install_guarded_fault_handler());
+        recurse(0);
+        unsafe { libc::_exit(0) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    // the stack overflow handler of `std` still runs, and aborts
+    assert!(libc::WIFSIGNALED(status));
+    assert_eq!(libc::WTERMSIG(status), libc::SIGABRT);
+}
+
+/// Handler installed before the guarded fault handler, which makes the faulting page writable.
+extern "C" fn unprotect(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let page = addr - addr % os_page_size();
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    unsafe { libc::mprotect(page as *mut libc::c_void, os_page_size(), prot) };
+}
+
+#[test]
+fn forward_unrelated_fault() {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        action.sa_sigaction = unprotect as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        unsafe { libc::sigaction(libc::SIGSEGV, &action, std::ptr::null_mut()) };
+        register_error(fault_handler);
+        assert!(install_guarded_fault_handler());
+
+        let size = 2 * os_page_size();
+        let map = unsafe {
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            libc::mmap(std::ptr::null_mut(), size, libc::PROT_NONE, flags, -1, 0)
+        };
+        assert_ne!(map, libc::MAP_FAILED);
+        // both faults are fixed by the previous handler
+        for offset in [0, os_page_size()] {
+            unsafe { black_box(map.cast::<u8>().add(offset)).write_volatile(42) };
+        }
+
+        // and guarded faults are still reported
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        let mut allocator = guarded_allocator(1);
+        let p = unsafe { allocator.alloc(layout) };
+        let addr = p as usize + layout.size();
+        for (expected, value) in EXPECTED.iter().zip([addr, p as usize, layout.size()]) {
+            expected.store(value, Ordering::Relaxed);
+        }
+        unsafe { black_box(addr as *mut u8).write_volatile(42) };
+        unsafe { libc::_exit(0) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 42);
+}
+
+#[cfg(all(feature = "thread_cache", feature = "std_mutex"))]
+#[test]
+fn thread_cache_sampling() {
+    use baby_mimalloc::MimallocCacheWrapper;
+
+    static ALLOCATOR: MimallocCacheWrapper<MmapAlloc> =
+        MimallocCacheWrapper::with_guard_pages(MmapAlloc);
+
+    std::thread::spawn(|| {
+        ALLOCATOR.set_guarded_sample_rate(1);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        // small blocks are not served from the cache of this thread
+        for _ in 0..100 {
+            let p = unsafe { ALLOCATOR.alloc(layout) };
+            assert!(is_guarded(p, layout));
+            unsafe { ALLOCATOR.dealloc(p, layout) };
+        }
+    })
+    .join()
+    .unwrap();
+}
+
+static DOUBLE_FREE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn double_free_handler(error: Error) {
+    assert!(matches!(error, Error::DoubleFree { .. }));
+    DOUBLE_FREE_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn quarantine() {
+    register_error(double_free_handler);
+    let mut allocator = guarded_allocator(1);
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    // more than the quarantine can hold, so the oldest slots are released
+    for _ in 0..100 {
+        let p = unsafe { allocator.alloc(layout) };
+        assert!(is_guarded(p, layout));
+        unsafe { allocator.dealloc(p, layout) };
+    }
+
+    let p = unsafe { allocator.alloc(layout) };
+    unsafe { allocator.dealloc(p, layout) };
+    unsafe { allocator.dealloc(p, layout) };
+    assert_eq!(DOUBLE_FREE_COUNT.load(Ordering::Relaxed), 1);
+}
+
+/// Check that all blocks are guarded with a sample rate of 1.
+fn assert_all_guarded(allocator: &impl GlobalAlloc) {
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    for _ in 0..10 {
+        let p = unsafe { allocator.alloc(layout) };
+        assert!(is_guarded(p, layout));
+        unsafe { allocator.dealloc(p, layout) };
+    }
+}
+
+#[test]
+fn wrapper_sampling() {
+    let allocator = unsafe { MimallocCell::with_guard_pages(MmapAlloc) };
+    allocator.set_guarded_sample_rate(1);
+    assert_all_guarded(&allocator);
+
+    #[cfg(feature = "std_mutex")]
+    {
+        let allocator = baby_mimalloc::MimallocShardedWrapper::<_, 4>::with_guard_pages(MmapAlloc);
+        allocator.set_guarded_sample_rate(1);
+        assert_all_guarded(&allocator);
+    }
+
+    #[cfg(feature = "critical_section")]
+    {
+        let allocator = baby_mimalloc::MimallocCriticalSection::with_guard_pages(MmapAlloc);
+        allocator.set_guarded_sample_rate(1);
+        assert_all_guarded(&allocator);
+    }
+
+    #[cfg(feature = "thread_local")]
+    std::thread::spawn(|| {
+        static ALLOCATOR: baby_mimalloc::MimallocThreadLocal<MmapAlloc> =
+            baby_mimalloc::MimallocThreadLocal::with_guard_pages(MmapAlloc);
+        ALLOCATOR.set_guarded_sample_rate(1);
+        assert_all_guarded(&ALLOCATOR);
+    })
+    .join()
+    .unwrap();
+}
+
+#[cfg(feature = "thread_local")]
+#[test]
+fn reclaim_live_guarded() {
+    use baby_mimalloc::MimallocThreadLocal;
+    use std::thread;
+
+    static ALLOCATOR: MimallocThreadLocal<MmapAlloc> =
+        MimallocThreadLocal::with_guard_pages(MmapAlloc);
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        register_error(fault_handler);
+        assert!(install_guarded_fault_handler());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        ALLOCATOR.set_guarded_sample_rate(1);
+        // the guarded block stays alive after its thread exits
+        let p = thread::spawn(move || unsafe { ALLOCATOR.alloc(layout) } as usize)
+            .join()
+            .unwrap();
+        ALLOCATOR.set_guarded_sample_rate(0);
+        // reclaim it, free it from another thread, and collect the free
+        ALLOCATOR.collect();
+        thread::spawn(move || unsafe { ALLOCATOR.dealloc(p as *mut u8, layout) })
+            .join()
+            .unwrap();
+        ALLOCATOR.collect();
+
+        // the slot is in the quarantine
+        for (expected, value) in EXPECTED.iter().zip([p, p, layout.size()]) {
+            expected.store(value, Ordering::Relaxed);
+        }
+        unsafe { black_box(p as *mut u8).write_volatile(42) };
+        unsafe { libc::_exit(0) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 43);
+}
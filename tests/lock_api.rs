@@ -0,0 +1,95 @@
+mod common;
+
+use baby_mimalloc::MimallocRawMutexWrapper;
+use lock_api::{GuardSend, RawMutex};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// A minimal spin lock implemented outside the crate.
+struct RawSpinLock(AtomicBool);
+
+unsafe impl RawMutex for RawSpinLock {
+    const INIT: Self = Self(AtomicBool::new(false));
+
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.0
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+static LOCK_COUNT: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+
+/// [`RawSpinLock`] that counts how many times it is acquired, separately for each `ID`.
+struct CountingLock<const ID: usize>(RawSpinLock);
+
+unsafe impl<const ID: usize> RawMutex for CountingLock<ID> {
+    const INIT: Self = Self(RawSpinLock::INIT);
+
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        self.0.lock();
+        LOCK_COUNT[ID].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.0.try_lock();
+        if locked {
+            LOCK_COUNT[ID].fetch_add(1, Ordering::Relaxed);
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        self.0.unlock();
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: MimallocRawMutexWrapper<RawSpinLock, System> =
+    MimallocRawMutexWrapper::with_os_allocator(System);
+
+#[test]
+fn multi_thread_alloc() {
+    common::local_alloc(8, 10_000);
+
+    ALLOCATOR.collect();
+}
+
+#[test]
+fn lock_per_instance() {
+    static FIRST: MimallocRawMutexWrapper<CountingLock<0>, System> =
+        MimallocRawMutexWrapper::with_os_allocator(System);
+    static SECOND: MimallocRawMutexWrapper<CountingLock<1>, System> =
+        MimallocRawMutexWrapper::with_os_allocator(System);
+    let lock_count = || {
+        LOCK_COUNT
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed))
+    };
+
+    let layout = Layout::new::<u64>();
+    let p = unsafe { FIRST.alloc(layout) };
+    assert_eq!(lock_count(), [1, 0]);
+    let q = unsafe { SECOND.alloc(layout) };
+    assert_eq!(lock_count(), [1, 1]);
+
+    // deallocation does not take the lock
+    unsafe { FIRST.dealloc(p, layout) };
+    unsafe { SECOND.dealloc(q, layout) };
+    assert_eq!(lock_count(), [1, 1]);
+}
@@ -0,0 +1,44 @@
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::Mimalloc;
+use std::alloc::{Layout, System};
+use std::sync::Mutex;
+
+static OVERFLOWS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+fn error_handler(error: Error) {
+    match error {
+        Error::BufferOverflow { ptr, size } => OVERFLOWS.lock().unwrap().push((ptr, size)),
+        _ => panic!("unexpected error: {error:?}"),
+    }
+}
+
+#[test]
+fn buffer_overflow() {
+    register_error(error_handler);
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    for (size, align) in [
+        (1, 1),
+        (8, 8),
+        (13, 8),
+        (100, 8),
+        (100, 64),
+        (10_000, 8),
+        (10_000, 4096),
+        (1_000_000, 8),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let blocks = Vec::from_iter((0..10).map(|_| unsafe { allocator.alloc(layout) }));
+        for &p in &blocks {
+            assert_eq!(p as usize % align, 0);
+            unsafe { p.write_bytes(0xFF, size) };
+            unsafe { allocator.dealloc(p, layout) };
+        }
+        assert!(OVERFLOWS.lock().unwrap().is_empty());
+
+        let p = unsafe { allocator.alloc(layout) };
+        unsafe { p.write_bytes(0xFF, size + 1) };
+        unsafe { allocator.dealloc(p, layout) };
+        assert_eq!(*OVERFLOWS.lock().unwrap(), [(p as usize, size)]);
+        OVERFLOWS.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,28 @@
+use baby_mimalloc::MmapAlloc;
+use std::alloc::{GlobalAlloc, Layout};
+
+const SEGMENT_SIZE: usize = 4 << 20;
+
+#[test]
+fn random_mmap() {
+    let layout = Layout::from_size_align(SEGMENT_SIZE, SEGMENT_SIZE).unwrap();
+    let segments = Vec::from_iter((0..16).map(|_| unsafe { MmapAlloc.alloc(layout) }));
+    for &p in &segments {
+        assert!(!p.is_null());
+        assert_eq!(p as usize % SEGMENT_SIZE, 0);
+        unsafe { p.write_bytes(0x37, SEGMENT_SIZE) };
+    }
+
+    if cfg!(target_pointer_width = "64") {
+        // the kernel places consecutive mappings next to each other
+        let adjacent = segments
+            .windows(2)
+            .filter(|w| (w[0] as usize).abs_diff(w[1] as usize) == SEGMENT_SIZE)
+            .count();
+        assert!(adjacent < 4, "adjacent: {adjacent}");
+    }
+
+    for p in segments {
+        unsafe { MmapAlloc.dealloc(p, layout) };
+    }
+}
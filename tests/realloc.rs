@@ -0,0 +1,101 @@
+use baby_mimalloc::{Mimalloc, MimallocCell};
+use std::alloc::{GlobalAlloc, Layout, System};
+
+fn fill(p: *mut u8, size: usize) {
+    for i in 0..size {
+        unsafe { p.add(i).write(i as u8) };
+    }
+}
+
+fn check(p: *mut u8, size: usize) {
+    for i in 0..size {
+        assert_eq!(unsafe { p.add(i).read() }, i as u8);
+    }
+}
+
+#[test]
+fn in_place() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    unsafe {
+        let layout = Layout::from_size_align(63, 8).unwrap();
+        let p = allocator.alloc(layout);
+        fill(p, 63);
+        // grow within the block
+        let q = allocator.realloc(p, layout, 64);
+        assert_eq!(p, q);
+        check(q, 63);
+        allocator.dealloc(q, Layout::from_size_align(64, 8).unwrap());
+
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let p = allocator.alloc(layout);
+        fill(p, 1024);
+        // shrink by less than half
+        let q = allocator.realloc(p, layout, 700);
+        assert_eq!(p, q);
+        check(q, 700);
+        allocator.dealloc(q, Layout::from_size_align(700, 8).unwrap());
+    }
+}
+
+#[test]
+fn moved() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    unsafe {
+        let layout = Layout::from_size_align(1024, 64).unwrap();
+        let p = allocator.alloc(layout);
+        fill(p, 1024);
+        // shrink to a smaller bin
+        let q = allocator.realloc(p, layout, 16);
+        assert_ne!(p, q);
+        assert!((q as usize).is_multiple_of(64));
+        check(q, 16);
+        // grow to a larger bin
+        let layout = Layout::from_size_align(16, 64).unwrap();
+        let r = allocator.realloc(q, layout, 100_000);
+        assert_ne!(q, r);
+        assert!((r as usize).is_multiple_of(64));
+        check(r, 16);
+        allocator.dealloc(r, Layout::from_size_align(100_000, 64).unwrap());
+    }
+}
+
+#[test]
+fn zeroed() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    unsafe {
+        // leave a dirty block to be reused by the move below
+        let dirty = Layout::from_size_align(4096, 8).unwrap();
+        let p = allocator.alloc(dirty);
+        p.write_bytes(0xff, 4096);
+        allocator.dealloc(p, dirty);
+
+        let layout = Layout::from_size_align(63, 8).unwrap();
+        let p = allocator.alloc(layout);
+        p.write_bytes(0xff, 63);
+        let q = allocator.realloc_zeroed(p, layout, 64);
+        assert_eq!(p, q);
+        assert_eq!(q.add(63).read(), 0);
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let r = allocator.realloc_zeroed(q, layout, 4096);
+        assert_ne!(q, r);
+        assert!((0..4096).all(|i| r.add(i).read() == if i < 63 { 0xff } else { 0 }));
+        allocator.dealloc(r, dirty);
+    }
+}
+
+#[test]
+fn global_alloc_realloc() {
+    let allocator = unsafe { MimallocCell::with_os_allocator(System) };
+    let mut layout = Layout::from_size_align(1, 8).unwrap();
+    let mut p = unsafe { allocator.alloc(layout) };
+    fill(p, 1);
+    for size in (2..100_000).step_by(97) {
+        p = unsafe { allocator.realloc(p, layout, size) };
+        check(p, layout.size());
+        fill(p, size);
+        layout = Layout::from_size_align(size, 8).unwrap();
+    }
+    unsafe { allocator.dealloc(p, layout) };
+    allocator.collect();
+}
@@ -0,0 +1,41 @@
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::MimallocMutexWrapper;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// OS allocator that allocates through the global allocator while it is locked.
+struct ReentrantAlloc;
+
+unsafe impl GlobalAlloc for ReentrantAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let nested = Box::new(42usize);
+        assert_eq!(*nested, 42);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: MimallocMutexWrapper<ReentrantAlloc> =
+    MimallocMutexWrapper::with_os_allocator(ReentrantAlloc);
+
+static REENTRANT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn error_handler(error: Error) {
+    assert_eq!(error, Error::Reentrant);
+    REENTRANT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn reentrant_alloc() {
+    register_error(error_handler);
+    let vec = Vec::from_iter((0..100).map(|i| vec![i; 100_000]));
+    assert!(vec
+        .iter()
+        .enumerate()
+        .all(|(i, v)| v.iter().all(|&x| x == i)));
+    assert!(REENTRANT_COUNT.load(Ordering::Relaxed) > 0);
+}
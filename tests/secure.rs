@@ -0,0 +1,64 @@
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::Mimalloc;
+use std::alloc::{Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static CORRUPTED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn error_handler(error: Error) {
+    assert!(matches!(error, Error::CorruptedFreeList { .. }));
+    CORRUPTED_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn corrupted_free_list() {
+    register_error(error_handler);
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let blocks = Vec::from_iter((0..100).map(|_| unsafe { allocator.alloc(layout) }));
+    for &p in &blocks {
+        unsafe { allocator.dealloc(p, layout) };
+    }
+    // overwrite the free list pointers, as a use-after-free write would do
+    for &p in &blocks {
+        unsafe { p.cast::<usize>().write(0x4141_4141_4141_4141) };
+    }
+    for _ in 0..200 {
+        let p = unsafe { allocator.alloc(layout) };
+        assert_ne!(p as usize, 0x4141_4141_4141_4141);
+        assert!(!p.is_null());
+        unsafe { p.write_bytes(0, layout.size()) };
+    }
+    assert!(CORRUPTED_COUNT.load(Ordering::Relaxed) > 0);
+}
+
+fn alloc_order(seed: Option<u64>) -> Vec<usize> {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    if let Some(seed) = seed {
+        allocator.set_random_seed(seed);
+    }
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let blocks = Vec::from_iter((0..64).map(|_| unsafe { allocator.alloc(layout) } as usize));
+    let mut sorted = blocks.clone();
+    sorted.sort_unstable();
+    // the block size may be larger than the requested size, e.g. with `padding`
+    let block_size = sorted.windows(2).map(|w| w[1] - w[0]).min().unwrap();
+    Vec::from_iter(blocks.iter().map(|&p| (p - sorted[0]) / block_size))
+}
+
+#[test]
+fn shuffled_free_list() {
+    let order = alloc_order(None);
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    assert_eq!(sorted.len(), 64);
+    if cfg!(not(feature = "padding")) {
+        // with `padding`, the blocks span more than one extension of the free list
+        assert_eq!(sorted, Vec::from_iter(0..64));
+    }
+    assert_ne!(order, sorted);
+
+    assert_eq!(alloc_order(Some(42)), alloc_order(Some(42)));
+    assert_ne!(alloc_order(Some(42)), alloc_order(Some(43)));
+}
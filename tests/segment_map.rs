@@ -0,0 +1,45 @@
+use baby_mimalloc::error::{register_error, Error};
+use baby_mimalloc::Mimalloc;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static INVALID_FREE: AtomicUsize = AtomicUsize::new(0);
+
+fn error_handler(error: Error) {
+    let Error::InvalidFree { ptr } = error else {
+        panic!("unexpected error: {error:?}");
+    };
+    INVALID_FREE.store(ptr, Ordering::Relaxed);
+}
+
+#[test]
+fn invalid_free() {
+    register_error(error_handler);
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+
+    let p = unsafe { allocator.alloc(layout) };
+    unsafe { allocator.dealloc(p, layout) };
+    assert_eq!(INVALID_FREE.load(Ordering::Relaxed), 0);
+
+    let foreign = unsafe { System.alloc(layout) };
+    unsafe { allocator.dealloc(foreign, layout) };
+    assert_eq!(INVALID_FREE.load(Ordering::Relaxed), foreign as usize);
+    unsafe { System.dealloc(foreign, layout) };
+
+    let mut local = [0u8; 100];
+    unsafe { allocator.dealloc(local.as_mut_ptr(), layout) };
+    assert_eq!(
+        INVALID_FREE.load(Ordering::Relaxed),
+        local.as_ptr() as usize
+    );
+}
+
+#[test]
+fn foreign_usable_size() {
+    let allocator = Mimalloc::with_os_allocator(System);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let foreign = unsafe { System.alloc(layout) };
+    assert_eq!(unsafe { allocator.usable_size(foreign) }, 0);
+    unsafe { System.dealloc(foreign, layout) };
+}
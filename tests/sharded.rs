@@ -0,0 +1,78 @@
+mod common;
+
+use baby_mimalloc::MimallocShardedWrapper;
+use common::SystemWithStat;
+use rand::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+#[global_allocator]
+static ALLOCATOR: MimallocShardedWrapper<System, 4> =
+    MimallocShardedWrapper::with_os_allocator(System);
+
+#[test]
+fn local_alloc() {
+    common::local_alloc(8, 10_000);
+}
+
+#[test]
+fn cross_thread_free() {
+    const N: usize = 1_000_000;
+
+    let (sender, receiver) = sync_channel::<Vec<u8>>(1024);
+
+    let producers = Vec::from_iter((0..4).map(|_| {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut rng = thread_rng();
+            for _ in 0..N / 4 {
+                sender.send(vec![42; rng.gen_range(1..1024)]).unwrap();
+            }
+        })
+    }));
+    drop(sender);
+
+    let consumer = thread::spawn(move || {
+        for vec in receiver {
+            assert!(vec.iter().all(|&x| x == 42));
+        }
+    });
+
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    consumer.join().unwrap();
+
+    ALLOCATOR.collect();
+}
+
+#[test]
+fn free_to_owning_shard() {
+    static SHARDED: MimallocShardedWrapper<SystemWithStat, 4> =
+        MimallocShardedWrapper::with_os_allocator(SystemWithStat);
+    const N: usize = 200_000;
+
+    let layout = Layout::new::<[u64; 8]>();
+    let alloc = || Vec::from_iter((0..N).map(|_| unsafe { SHARDED.alloc(layout) } as usize));
+
+    let blocks = alloc();
+    let peak = SystemWithStat::peak();
+    thread::spawn(move || {
+        for p in blocks {
+            unsafe { SHARDED.dealloc(p as _, layout) };
+        }
+    })
+    .join()
+    .unwrap();
+
+    // blocks in full pages are returned to the shard of this thread, which owns their segments,
+    // otherwise they are not collected and the memory is allocated again
+    let blocks = alloc();
+    let new_peak = SystemWithStat::peak();
+    let threshold = peak + 4 * 1024 * 1024;
+    assert!(new_peak <= threshold, "peak: {new_peak} > {threshold}");
+    for p in blocks {
+        unsafe { SHARDED.dealloc(p as _, layout) };
+    }
+}
@@ -5,6 +5,10 @@ use std::array::from_fn;
 use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
+/// Extra bytes per allocation, for the canary of the `padding` feature.
+#[cfg(feature = "padding")]
+const PADDING: usize = 2 * size_of::<usize>();
+
 fn test_alloc<A: GlobalAlloc>(
     allocator: &mut Mimalloc<A>,
     size: usize,
@@ -12,7 +16,10 @@ fn test_alloc<A: GlobalAlloc>(
 ) -> (*mut u8, Layout) {
     let layout = Layout::from_size_align(size, align).unwrap();
     let p = unsafe { allocator.alloc(layout) };
-    assert!(p as usize % align == 0, "p: {p:?}, align: {align}");
+    assert!(
+        (p as usize).is_multiple_of(align),
+        "p: {p:?}, align: {align}"
+    );
     unsafe { p.write_bytes(0x37, size) };
     (p, layout)
 }
@@ -110,7 +117,10 @@ fn alloc_dealloc() {
     }
 
     let peak = os_alloc.0.lock().unwrap().peak;
+    #[cfg(not(feature = "padding"))]
     let threshold = const { (N * 9).next_multiple_of(4 * 1024 * 1024) };
+    #[cfg(feature = "padding")]
+    let threshold = const { (N * (9 + 2 * PADDING)).next_multiple_of(4 * 1024 * 1024) };
     assert!(peak <= threshold, "peak: {peak} > {threshold}");
     assert!(peak >= threshold / 2, "peak: {peak} < {threshold} / 2");
 }
@@ -149,7 +159,12 @@ fn random_alloc_dealloc_small() {
     }
 
     let peak = os_alloc.0.lock().unwrap().peak;
+    #[cfg(not(feature = "padding"))]
     let threshold = const { (N * 5 * K * (K + 1)).next_multiple_of(4 * 1024 * 1024) };
+    #[cfg(feature = "padding")]
+    // a quarter more than the largest live size, as without padding
+    let threshold =
+        const { (N * 5 * (K * (K + 1) + K * PADDING / 4)).next_multiple_of(4 * 1024 * 1024) };
     assert!(peak <= threshold, "peak: {peak} > {threshold}");
     assert!(peak >= threshold / 2, "peak: {peak} < {threshold} / 2");
 }
@@ -192,7 +207,12 @@ fn random_alloc_dealloc_small_collect() {
     }
 
     let peak = os_alloc.0.lock().unwrap().peak;
+    #[cfg(not(feature = "padding"))]
     let threshold = const { (N * 5 * K * (K + 1)).next_multiple_of(4 * 1024 * 1024) };
+    #[cfg(feature = "padding")]
+    // a quarter more than the largest live size, as without padding
+    let threshold =
+        const { (N * 5 * (K * (K + 1) + K * PADDING / 4)).next_multiple_of(4 * 1024 * 1024) };
     assert!(peak <= threshold, "peak: {peak} > {threshold}");
     assert!(peak >= threshold / 2, "peak: {peak} < {threshold} / 2");
 }
@@ -0,0 +1,98 @@
+mod common;
+
+use baby_mimalloc::MimallocCacheWrapper;
+use common::SystemWithStat;
+use rand::prelude::*;
+#[cfg(not(feature = "secure"))]
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+#[global_allocator]
+static ALLOCATOR: MimallocCacheWrapper<SystemWithStat> =
+    MimallocCacheWrapper::with_os_allocator(SystemWithStat);
+
+#[test]
+fn local_alloc() {
+    let _serial = common::serial();
+    common::local_alloc(8, 2_000);
+}
+
+#[test]
+fn cross_thread_free() {
+    let _serial = common::serial();
+    const N: usize = 1_000_000;
+
+    let (sender, receiver) = sync_channel::<Box<[u8]>>(1024);
+
+    let producer = thread::spawn(move || {
+        let mut rng = thread_rng();
+        for _ in 0..N {
+            sender
+                .send(vec![42; rng.gen_range(1..1024)].into_boxed_slice())
+                .unwrap();
+        }
+    });
+
+    let consumer = thread::spawn(move || {
+        for boxed in receiver {
+            assert!(boxed.iter().all(|&x| x == 42));
+        }
+    });
+
+    producer.join().unwrap();
+    consumer.join().unwrap();
+
+    // the blocks cached by the consumer are returned when it exits
+    ALLOCATOR.collect();
+    let peak = SystemWithStat::peak();
+    let threshold = 1 << 30;
+    assert!(peak <= threshold, "peak: {peak} > {threshold}");
+}
+
+// the cache is disabled with `secure`
+#[cfg(not(feature = "secure"))]
+#[test]
+fn flush_magazine() {
+    let _serial = common::serial();
+    thread::spawn(|| {
+        const N: usize = 1_000_000;
+        let layout = Layout::new::<[u64; 6]>();
+        let blocks = Vec::from_iter((0..N).map(|_| unsafe { ALLOCATOR.alloc(layout) }));
+        let used = SystemWithStat::used();
+        for p in blocks {
+            unsafe { ALLOCATOR.dealloc(p, layout) };
+        }
+        // at most 64 blocks stay in the magazine, the others are flushed to the heap,
+        // which frees the empty pages
+        let freed = used - SystemWithStat::used();
+        let threshold = N * layout.size() / 2;
+        assert!(freed >= threshold, "freed: {freed} < {threshold}");
+    })
+    .join()
+    .unwrap();
+}
+
+#[cfg(all(
+    any(feature = "zero_on_free", feature = "fill_on_free"),
+    not(feature = "secure")
+))]
+#[test]
+fn fill_cached_blocks() {
+    thread::spawn(|| {
+        let layout = Layout::new::<[u64; 4]>();
+        let p = unsafe { ALLOCATOR.alloc(layout) };
+        unsafe { p.write_bytes(0xAA, layout.size()) };
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+        // the block stays in the magazine of this thread
+        let bytes = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+        let fill = if cfg!(feature = "zero_on_free") {
+            0
+        } else {
+            0xDF
+        };
+        assert!(bytes[size_of::<usize>()..].iter().all(|&byte| byte == fill));
+    })
+    .join()
+    .unwrap();
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use baby_mimalloc::MimallocThreadLocal;
+use common::SystemWithStat;
+use rand::prelude::*;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+#[global_allocator]
+static ALLOCATOR: MimallocThreadLocal<SystemWithStat> =
+    MimallocThreadLocal::with_os_allocator(SystemWithStat);
+
+#[test]
+fn local_alloc() {
+    common::local_alloc(8, 10_000);
+}
+
+#[test]
+fn cross_thread_free() {
+    const N: usize = 1_000_000;
+    const M: usize = 1024;
+
+    let (sender, receiver) = sync_channel::<Vec<u8>>(M);
+
+    let producer = thread::spawn(move || {
+        let mut rng = thread_rng();
+        for _ in 0..N {
+            let len = if rng.gen_ratio(1, 1000) {
+                rng.gen_range(1..4 * 1024 * 1024)
+            } else {
+                rng.gen_range(1..1024)
+            };
+            sender.send(vec![42; len]).unwrap();
+        }
+    });
+
+    let consumer = thread::spawn(move || {
+        for vec in receiver {
+            assert!(vec.iter().all(|&x| x == 42));
+        }
+    });
+
+    producer.join().unwrap();
+    consumer.join().unwrap();
+
+    // without reclaiming blocks freed by the consumer, the producer would need several GiB
+    let peak = SystemWithStat::peak();
+    let threshold = 1 << 30;
+    assert!(peak <= threshold, "peak: {peak} > {threshold}");
+}
+
+#[test]
+fn reclaim_abandoned() {
+    const T: usize = 50;
+    const N: usize = 100_000;
+
+    let mut alive = Vec::new();
+
+    for _ in 0..T {
+        let handles = Vec::from_iter((0..4).map(|_| {
+            thread::spawn(|| {
+                let mut rng = thread_rng();
+                let mut vec = Vec::from_iter((0..N).map(|_| vec![42u8; rng.gen_range(1..128)]));
+                // keep some blocks alive after the thread exits
+                vec.split_off(N - N / 100)
+            })
+        }));
+        let new_alive = Vec::from_iter(handles.into_iter().flat_map(|h| h.join().unwrap()));
+        assert!(alive.iter().flatten().all(|&x| x == 42));
+        alive = new_alive;
+    }
+
+    // each thread needs ~12 MiB, without reclaiming the total would be ~2 GiB
+    let peak = SystemWithStat::peak();
+    let threshold = 1 << 30;
+    assert!(peak <= threshold, "peak: {peak} > {threshold}");
+}
+
+#[test]
+fn exit_while_reclaiming() {
+    // threads exit and abandon their segments while new threads reclaim them
+    let handles = Vec::from_iter((0..8).map(|_| {
+        thread::spawn(|| {
+            for _ in 0..200 {
+                thread::spawn(|| {
+                    let mut rng = thread_rng();
+                    let vec = Vec::from_iter((0..1000).map(|_| vec![42u8; rng.gen_range(1..4096)]));
+                    assert!(vec.iter().flatten().all(|&x| x == 42));
+                })
+                .join()
+                .unwrap();
+            }
+        })
+    }));
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn alloc_after_exit() {
+    unsafe extern "C" fn destructor(value: *mut libc::c_void) {
+        // pthread key destructors run after the thread-local ones, so the heap is abandoned
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let vec = Vec::from_iter((0..1000).map(|_| vec![42u8; rng.gen_range(1..1024)]));
+            assert!(vec.iter().flatten().all(|&x| x == 42));
+        }
+        drop(unsafe { Box::from_raw(value.cast::<Vec<u8>>()) });
+    }
+
+    let mut key = 0;
+    assert_eq!(
+        unsafe { libc::pthread_key_create(&mut key, Some(destructor)) },
+        0
+    );
+
+    let handles = Vec::from_iter((0..8).map(|_| {
+        thread::spawn(move || {
+            let value = Box::into_raw(Box::new(vec![42u8; 1024]));
+            assert_eq!(unsafe { libc::pthread_setspecific(key, value.cast()) }, 0);
+        })
+    }));
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    unsafe { libc::pthread_key_delete(key) };
+}
@@ -0,0 +1,58 @@
+use baby_mimalloc::{Mimalloc, MimallocCell};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ptr::null;
+
+#[test]
+fn usable_size() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    assert_eq!(unsafe { allocator.usable_size(null()) }, 0);
+    let allocation = Vec::from_iter((0..3000).map(|i| {
+        let size = 1 + i * 97 % 20_000;
+        let align = 1 << (i % 13);
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let p = unsafe { allocator.alloc(layout) };
+        let usable_size = unsafe { allocator.usable_size(p) };
+        if cfg!(feature = "padding") {
+            assert_eq!(usable_size, size);
+        } else {
+            assert!(
+                usable_size >= size,
+                "size {size}, usable size {usable_size}"
+            );
+        }
+        // the slack can be used
+        unsafe { p.write_bytes(0x37, usable_size) };
+        (p, layout)
+    }));
+    for (p, layout) in allocation {
+        unsafe { allocator.dealloc(p, layout) };
+    }
+
+    let layout = Layout::from_size_align(10 << 20, 8).unwrap();
+    let p = unsafe { allocator.alloc(layout) };
+    assert!(unsafe { allocator.usable_size(p) } >= layout.size());
+    unsafe { allocator.dealloc(p, layout) };
+}
+
+#[cfg(not(feature = "padding"))]
+#[test]
+fn usable_size_of_size_class() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let p = unsafe { allocator.alloc(layout) };
+    // 100 bytes are rounded up to a size class
+    let usable_size = unsafe { allocator.usable_size(p) };
+    assert!(usable_size > 100);
+    // growing into the slack does not move the block
+    assert_eq!(unsafe { allocator.realloc(p, layout, usable_size) }, p);
+    unsafe { allocator.dealloc(p, Layout::from_size_align(usable_size, 8).unwrap()) };
+}
+
+#[test]
+fn global_alloc_usable_size() {
+    let allocator = unsafe { MimallocCell::with_os_allocator(System) };
+    let layout = Layout::from_size_align(1000, 64).unwrap();
+    let p = unsafe { allocator.alloc(layout) };
+    assert!(unsafe { allocator.usable_size(p) } >= layout.size());
+    unsafe { allocator.dealloc(p, layout) };
+}
@@ -0,0 +1,18 @@
+use baby_mimalloc::Mimalloc;
+use std::alloc::{Layout, System};
+
+#[test]
+fn zero_on_free() {
+    let mut allocator = Mimalloc::with_os_allocator(System);
+    for size in [8, 100, 10_000, 100_000] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let p = unsafe { allocator.alloc(layout) };
+        unsafe { p.write_bytes(0xAA, size) };
+        // keep the page alive so that the freed block can be inspected
+        let q = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(p, layout) };
+        let bytes = unsafe { std::slice::from_raw_parts(p, size) };
+        assert!(bytes[size_of::<usize>()..].iter().all(|&byte| byte == 0));
+        unsafe { allocator.dealloc(q, layout) };
+    }
+}